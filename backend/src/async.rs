@@ -17,7 +17,7 @@ use futures::task::{
 use futures::executor::{Notify, NotifyHandle, spawn};
 use serde_json;
 use event::{AsyncOutcome, SpanId, TraceEvent};
-use state::TRACER_STATE;
+use state::{TRACER_STATE, TracerState};
 
 /// Atomic slot of a single parked task.  Note that this only parks at most one
 /// task: If your data-structure needs to wakeup potentially many threads, using
@@ -53,26 +53,126 @@ pub trait TraceFuture: Future + Sized where Self::Error : Debug {
 
     fn with_metadata<S: Into<String>>(self, name: S, meta: serde_json::Value) -> TracedFuture<Self> {
         TracedFuture {
-            state: TraceState::Created { name: name.into(), metadata: meta },
+            state: TraceState::new(name.into(), meta),
             inner: self,
         }
     }
 }
 impl<F: Future + Sized> TraceFuture for F where F::Error : Debug {}
 
-enum TraceState {
+pub enum TraceState {
     Created {
         name: String,
         metadata: serde_json::Value,
+        parent: Option<SpanId>,
     },
-    Executing {
-        parent: SpanId,
-        id: SpanId,
-    },
+    Executing(ActiveSpan),
     Resolved,
     Poisoned,
 }
 
+impl TraceState {
+    /// The future's parent is whatever span is current when it's created, so it stays put even if
+    /// an executor later polls it from a different thread.  Futures created outside of any span
+    /// get the span that's current when they're first polled instead.
+    pub fn new(name: String, metadata: serde_json::Value) -> Self {
+        let parent = TRACER_STATE.try_with(|c| c.borrow().current_span).ok().and_then(|p| p);
+        TraceState::Created { name, metadata, parent }
+    }
+
+    /// Move onto the CPU for a single poll, emitting `AsyncStart` on the first one.  Returns the
+    /// span that was previously current on this thread, which `end_poll` restores, and our span,
+    /// which is now current.
+    pub fn begin_poll(&mut self, st: &mut TracerState) -> (Option<SpanId>, SpanId) {
+        let span = match mem::replace(self, TraceState::Poisoned) {
+            // First poll!  Let's set up our execution state.
+            TraceState::Created { name, metadata, parent } => {
+                let span_id = SpanId::new();
+                let parent_id = parent.or(st.current_span).expect("Missing parent span");
+
+                let event = TraceEvent::AsyncStart {
+                    name: name,
+                    id: span_id,
+                    parent_id: parent_id,
+                    ts: st.now(),
+                    metadata: metadata,
+                };
+                st.emit(event);
+                ActiveSpan { id: span_id }
+            },
+            TraceState::Executing(span) => span,
+            TraceState::Resolved => panic!("Polled after resolved"),
+            TraceState::Poisoned => panic!("Polled after panic"),
+        };
+        let span_id = span.id;
+        *self = TraceState::Executing(span);
+
+        let on_event = TraceEvent::AsyncOnCPU {
+            id: span_id,
+            ts: st.now(),
+            thread_id: st.thread,
+        };
+        st.emit(on_event);
+        let previous = st.current_span.replace(span_id);
+
+        (previous, span_id)
+    }
+
+    /// Move back off the CPU after a poll, ending the span if the future resolved with `outcome`.
+    pub fn end_poll(&mut self, st: &mut TracerState, previous: Option<SpanId>, outcome: Option<AsyncOutcome>) {
+        let span_id = match *self {
+            TraceState::Executing(ref span) => span.id,
+            _ => panic!("Ended poll without beginning it"),
+        };
+        st.current_span = previous;
+        let off_event = TraceEvent::AsyncOffCPU {
+            id: span_id,
+            ts: st.now(),
+        };
+        st.emit(off_event);
+
+        if let Some(outcome) = outcome {
+            if let TraceState::Executing(span) = mem::replace(self, TraceState::Resolved) {
+                span.end(st, outcome);
+            }
+        }
+    }
+}
+
+/// A span that has started but not yet ended.  If it's dropped without calling `end`, which happens
+/// when its future is dropped before resolving (e.g. the loser of a `select` or a future abandoned
+/// by a timeout), it ends with `AsyncOutcome::Cancelled`.
+pub struct ActiveSpan {
+    id: SpanId,
+}
+
+impl ActiveSpan {
+    fn end(self, st: &mut TracerState, outcome: AsyncOutcome) {
+        let end_event = TraceEvent::AsyncEnd {
+            id: self.id,
+            ts: st.now(),
+            outcome,
+        };
+        st.emit(end_event);
+        mem::forget(self);
+    }
+}
+
+impl Drop for ActiveSpan {
+    fn drop(&mut self) {
+        // The thread's tracer state may already be gone if we're dropped during thread teardown.
+        let _ = TRACER_STATE.try_with(|c| {
+            let mut st = c.borrow_mut();
+            let end_event = TraceEvent::AsyncEnd {
+                id: self.id,
+                ts: st.now(),
+                outcome: AsyncOutcome::Cancelled,
+            };
+            st.emit(end_event);
+        });
+    }
+}
+
 pub struct TracedFuture<F> {
     state: TraceState,
     inner: F,
@@ -103,47 +203,7 @@ impl<F: Future> Future for TracedFuture<F> where F::Error : Debug {
 
     fn poll(&mut self) -> Poll<F::Item, F::Error> {
         TRACER_STATE.with(|c| {
-            let (parent_id, span_id) = {
-                let mut st = c.borrow_mut();
-                let (parent_id, span_id) = match mem::replace(&mut self.state, TraceState::Poisoned) {
-                    // First poll!  Let's set up our execution state.
-                    TraceState::Created { name, metadata } => {
-                        let span_id = SpanId::new();
-                        let parent_id = st.current_span.expect("Missing parent span");
-
-                        let event = TraceEvent::AsyncStart {
-                            name: name,
-                            id: span_id,
-                            parent_id: parent_id,
-                            ts: st.now(),
-                            metadata: metadata,
-                        };
-                        st.emit(event);
-
-                        self.state = TraceState::Executing {
-                            parent: parent_id,
-                            id: span_id,
-                        };
-                        (parent_id, span_id)
-                    },
-                    TraceState::Executing { parent, id } => {
-                        assert_eq!(st.current_span, Some(parent), "Parent span changed across execution");
-                        self.state = TraceState::Executing { parent, id };
-                        (parent, id)
-                    },
-                    TraceState::Resolved => panic!("Polled after resolved"),
-                    TraceState::Poisoned => panic!("Polled after panic"),
-                };
-
-                let on_event = TraceEvent::AsyncOnCPU {
-                    id: span_id,
-                    ts: st.now(),
-                };
-                st.emit(on_event);
-                st.current_span = Some(span_id);
-
-                (parent_id, span_id)
-            };
+            let (previous, span_id) = self.state.begin_poll(&mut c.borrow_mut());
 
             let notifier = Notifier { parent_task: AtomicTask::default(), parked_span: span_id };
             notifier.parent_task.park();
@@ -154,36 +214,12 @@ impl<F: Future> Future for TracedFuture<F> where F::Error : Debug {
                 f.poll_future_notify(&handle, 0)
             };
 
-            let mut st = c.borrow_mut();
-
-            st.current_span = Some(parent_id);
-            let off_event = TraceEvent::AsyncOffCPU {
-                id: span_id,
-                ts: st.now(),
+            let outcome = match result {
+                Ok(Async::Ready(..)) => Some(AsyncOutcome::Success),
+                Err(ref e) => Some(AsyncOutcome::Error(format!("{:?}", e))),
+                Ok(Async::NotReady) => None,
             };
-            st.emit(off_event);
-
-            match result {
-                Ok(Async::Ready(..)) => {
-                    self.state = TraceState::Resolved;
-                    let end_event = TraceEvent::AsyncEnd {
-                        id: span_id,
-                        ts: st.now(),
-                        outcome: AsyncOutcome::Success,
-                    };
-                    st.emit(end_event);
-                },
-                Err(ref e) => {
-                    self.state = TraceState::Resolved;
-                    let end_event = TraceEvent::AsyncEnd {
-                        id: span_id,
-                        ts: st.now(),
-                        outcome: AsyncOutcome::Error(format!("{:?}", e)),
-                    };
-                    st.emit(end_event);
-                },
-                Ok(Async::NotReady) => (),
-            }
+            self.state.end_poll(&mut c.borrow_mut(), previous, outcome);
             result
         })
     }
@@ -196,30 +232,36 @@ struct Notifier {
 
 impl Notify for Notifier {
     fn notify(&self, _: usize) {
-        TRACER_STATE.with(|c| {
-            let should_log = {
-                let mut st = c.borrow_mut();
-                let should_log = !st.currently_logging_wakeup;
-                if should_log {
-                    if let Some(current_span) = st.current_span {
-                        let event = TraceEvent::Wakeup {
-                            waking_span: current_span,
-                            parked_span: self.parked_span,
-                            ts: st.now(),
-                        };
-                        st.emit(event);
-                    }
-                    st.currently_logging_wakeup = true;
-                }
-                should_log
-            };
-
-            self.parent_task.notify();
+        log_wakeup(self.parked_span, || self.parent_task.notify());
+    }
+}
 
+/// Record that the current span is waking up `parked_span` and then run `wake`.  Wakeups that
+/// happen while we're already logging one (e.g. a chain of traced futures) are only logged once.
+pub fn log_wakeup<F: FnOnce()>(parked_span: SpanId, wake: F) {
+    TRACER_STATE.with(|c| {
+        let should_log = {
+            let mut st = c.borrow_mut();
+            let should_log = !st.currently_logging_wakeup;
             if should_log {
-                let mut st = c.borrow_mut();
-                st.currently_logging_wakeup = false;
+                if let Some(current_span) = st.current_span {
+                    let event = TraceEvent::Wakeup {
+                        waking_span: current_span,
+                        parked_span,
+                        ts: st.now(),
+                    };
+                    st.emit(event);
+                }
+                st.currently_logging_wakeup = true;
             }
-        })
-    }
+            should_log
+        };
+
+        wake();
+
+        if should_log {
+            let mut st = c.borrow_mut();
+            st.currently_logging_wakeup = false;
+        }
+    })
 }
@@ -0,0 +1,205 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle, Thread};
+use std::time::Duration;
+use crossbeam_queue::ArrayQueue;
+
+use event::TraceEvent;
+use state::Logger;
+
+// How long the writer thread sleeps when every queue is empty.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+struct Shared {
+    queues: Mutex<Vec<Arc<ArrayQueue<TraceEvent>>>>,
+    capacity: usize,
+    dropped: AtomicUsize,
+    shutdown: AtomicBool,
+    // Loggers partway through pushing an event, which the writer waits for before its last drain.
+    pushing: AtomicUsize,
+    // Flushes asked for, and how many of them the writer has done, which is everything once it's
+    // exited.
+    flush_requests: AtomicUsize,
+    flushed: Mutex<usize>,
+    flushed_cond: Condvar,
+}
+
+impl Shared {
+    fn set_flushed(&self, flushed: usize) {
+        *self.flushed.lock().unwrap_or_else(|e| e.into_inner()) = flushed;
+        self.flushed_cond.notify_all();
+    }
+}
+
+// Tells loggers waiting on a flush that there's nothing more to wait for when the writer thread
+// exits, even if it panicked.
+struct ExitGuard(Arc<Shared>);
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        self.0.set_flushed(usize::MAX);
+    }
+}
+
+/// Owner of a background thread that serializes events to an inner `Logger`, so traced threads
+/// only pay for pushing onto a lock-free queue.  Each `BackgroundLogger` gets its own bounded
+/// queue, and events that don't fit are dropped and counted rather than blocking.
+///
+/// Events are written in timestamp order within each batch the writer drains, but an event that
+/// sits in its queue across a batch boundary can still be written after later events from other
+/// threads.
+///
+/// Flushing a `BackgroundLogger` waits for the writer to drain every queue and flush the inner
+/// logger.  Dropping the `BackgroundWriter` does the same and waits for the writer thread to exit.
+/// Events logged after that are dropped.
+pub struct BackgroundWriter {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BackgroundWriter {
+    /// Start the writer thread, with room for `capacity` events in each logger's queue (at least
+    /// one).
+    pub fn new<L: Logger + 'static>(mut logger: L, capacity: usize) -> Self {
+        let shared = Arc::new(Shared {
+            queues: Mutex::new(Vec::new()),
+            capacity: capacity.max(1),
+            dropped: AtomicUsize::new(0),
+            shutdown: AtomicBool::new(false),
+            pushing: AtomicUsize::new(0),
+            flush_requests: AtomicUsize::new(0),
+            flushed: Mutex::new(0),
+            flushed_cond: Condvar::new(),
+        });
+        let shared_ = shared.clone();
+        let thread = thread::Builder::new()
+            .name("cyclotron-writer".into())
+            .spawn(move || {
+                let _guard = ExitGuard(shared_.clone());
+                let mut batch = Vec::new();
+                let mut flushed = 0;
+                loop {
+                    // Check for shutdown before draining so we always drain once more afterwards, once
+                    // any pushes that didn't see it have finished.
+                    let shutdown = shared_.shutdown.load(Ordering::SeqCst);
+                    // Likewise, a flush asked for before this has everything logged before it in
+                    // the queues we're about to drain.
+                    let flush_requests = shared_.flush_requests.load(Ordering::SeqCst);
+                    if shutdown {
+                        while shared_.pushing.load(Ordering::SeqCst) != 0 {
+                            thread::yield_now();
+                        }
+                    }
+                    let mut queues = shared_.queues.lock().unwrap();
+                    for queue in queues.iter() {
+                        while let Some(event) = queue.pop() {
+                            batch.push(event);
+                        }
+                    }
+                    // A queue whose logger has been dropped with events left in it is done once
+                    // we've drained it.
+                    queues.retain(|queue| Arc::strong_count(queue) > 1 || !queue.is_empty());
+                    drop(queues);
+                    if batch.is_empty() {
+                        if shutdown {
+                            break;
+                        }
+                    } else {
+                        batch.sort_by_key(|e| e.ts());
+                        for event in batch.drain(..) {
+                            logger.write(event);
+                        }
+                    }
+                    if flush_requests != flushed {
+                        logger.flush();
+                        flushed = flush_requests;
+                        shared_.set_flushed(flushed);
+                    } else if !shutdown {
+                        thread::park_timeout(DRAIN_INTERVAL);
+                    }
+                }
+                logger.flush();
+            })
+            .expect("Failed to spawn writer thread");
+
+        BackgroundWriter {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Create a logger with its own queue, typically one per `TracedThread`.
+    pub fn logger(&self) -> BackgroundLogger {
+        let queue = Arc::new(ArrayQueue::new(self.shared.capacity));
+        self.shared.queues.lock().unwrap().push(queue.clone());
+        BackgroundLogger {
+            queue,
+            shared: self.shared.clone(),
+            writer: self.thread.as_ref().unwrap().thread().clone(),
+        }
+    }
+
+    /// Number of events dropped because their queue was full or the writer had shut down.
+    pub fn dropped(&self) -> usize {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for BackgroundWriter {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            // We may be unwinding already, so don't panic again.
+            if thread.join().is_err() {
+                eprintln!("cyclotron: background writer thread panicked");
+            }
+        }
+    }
+}
+
+pub struct BackgroundLogger {
+    queue: Arc<ArrayQueue<TraceEvent>>,
+    shared: Arc<Shared>,
+    writer: Thread,
+}
+
+impl BackgroundLogger {
+    /// Same as `BackgroundWriter::dropped`, which counts drops across all loggers.
+    pub fn dropped(&self) -> usize {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Logger for BackgroundLogger {
+    fn write(&mut self, event: TraceEvent) {
+        // Either we see the shutdown, or the writer sees us pushing and waits for us.
+        self.shared.pushing.fetch_add(1, Ordering::SeqCst);
+        if self.shared.shutdown.load(Ordering::SeqCst) || self.queue.push(event).is_err() {
+            self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.shared.pushing.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    // Wait for the writer to write everything logged so far, by any logger, and flush the inner
+    // logger.
+    fn flush(&mut self) {
+        let request = self.shared.flush_requests.fetch_add(1, Ordering::SeqCst) + 1;
+        self.writer.unpark();
+        let mut flushed = self.shared.flushed.lock().unwrap_or_else(|e| e.into_inner());
+        while *flushed < request {
+            flushed = self.shared.flushed_cond.wait(flushed).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+impl Drop for BackgroundLogger {
+    // Stop the writer visiting our queue, unless there's still something in it, in which case the
+    // writer drops it after draining it.
+    fn drop(&mut self) {
+        let mut queues = self.shared.queues.lock().unwrap();
+        if self.queue.is_empty() {
+            queues.retain(|queue| !Arc::ptr_eq(queue, &self.queue));
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::time::Duration;
+use serde_json;
+
+use event::{AsyncOutcome, SpanId, TraceEvent};
+use state::Logger;
+
+/// Every binary trace starts with these bytes, which can't begin a JSON trace.
+pub const MAGIC: &[u8] = b"\0CYCLOTRON\x01";
+
+// Record tags.  Each event is a tag followed by its fields; `STRING` defines the next entry in the
+// string table, which start events then refer to by index.
+const STRING: u8 = 0;
+const ASYNC_START: u8 = 1;
+const ASYNC_ON_CPU: u8 = 2;
+const ASYNC_OFF_CPU: u8 = 3;
+const ASYNC_END: u8 = 4;
+const SYNC_START: u8 = 5;
+const SYNC_END: u8 = 6;
+const THREAD_START: u8 = 7;
+const THREAD_END: u8 = 8;
+const WAKEUP: u8 = 9;
+
+const OUTCOME_SUCCESS: u8 = 0;
+const OUTCOME_CANCELLED: u8 = 1;
+const OUTCOME_ERROR: u8 = 2;
+
+// Span references are `REF_NONE`, `REF_RAW` followed by the full `SpanId`, or `REF_INDEX + n` for
+// the `n`th span started in this file.
+const REF_NONE: u64 = 0;
+const REF_RAW: u64 = 1;
+const REF_INDEX: u64 = 2;
+
+fn write_varint<W: Write>(w: &mut W, mut v: u64) -> io::Result<()> {
+    let mut buf = [0u8; 10];
+    let mut i = 0;
+    while v >= 0x80 {
+        buf[i] = (v as u8) | 0x80;
+        v >>= 7;
+        i += 1;
+    }
+    buf[i] = v as u8;
+    w.write_all(&buf[..i + 1])
+}
+
+fn read_varint<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut v = 0u64;
+    for shift in (0..10).map(|i| i * 7) {
+        let mut byte = [0u8];
+        r.read_exact(&mut byte)?;
+        v |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "Varint too long"))
+}
+
+fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    write_varint(w, bytes.len() as u64)?;
+    w.write_all(bytes)
+}
+
+fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_varint(r)? as usize;
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_string<R: Read>(r: &mut R) -> io::Result<String> {
+    String::from_utf8(read_bytes(r)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// `Logger` that writes the compact binary format read by `BinaryReader`.
+pub struct BinaryWriter {
+    file: BufWriter<File>,
+    strings: HashMap<String, u64>,
+    // Indices of spans that have started but not ended.  Once a span ends we forget its index, and
+    // any later references to it are written in full.
+    spans: HashMap<SpanId, u64>,
+    num_spans: u64,
+    last_ts: u64,
+}
+
+impl BinaryWriter {
+    pub fn new(f: File) -> Self {
+        let mut file = BufWriter::new(f);
+        file.write_all(MAGIC).expect("Failed to write to logfile");
+        BinaryWriter {
+            file,
+            strings: HashMap::new(),
+            spans: HashMap::new(),
+            num_spans: 0,
+            last_ts: 0,
+        }
+    }
+
+    fn string(&mut self, s: String) -> io::Result<u64> {
+        if let Some(&index) = self.strings.get(&s) {
+            return Ok(index);
+        }
+        let index = self.strings.len() as u64;
+        self.file.write_all(&[STRING])?;
+        write_bytes(&mut self.file, s.as_bytes())?;
+        self.strings.insert(s, index);
+        Ok(index)
+    }
+
+    fn new_span(&mut self, id: SpanId) -> io::Result<()> {
+        write_varint(&mut self.file, id.0)?;
+        self.spans.insert(id, self.num_spans);
+        self.num_spans += 1;
+        Ok(())
+    }
+
+    fn span_ref(&mut self, id: Option<SpanId>) -> io::Result<()> {
+        match id {
+            None => write_varint(&mut self.file, REF_NONE),
+            Some(id) => match self.spans.get(&id) {
+                Some(&index) => write_varint(&mut self.file, REF_INDEX + index),
+                None => {
+                    write_varint(&mut self.file, REF_RAW)?;
+                    write_varint(&mut self.file, id.0)
+                },
+            },
+        }
+    }
+
+    // Timestamps are zigzag-encoded deltas from the previous event, since events from different
+    // threads can arrive slightly out of order.
+    fn ts(&mut self, ts: Duration) -> io::Result<()> {
+        let nanos = ts.as_secs() * 1_000_000_000 + ts.subsec_nanos() as u64;
+        let delta = nanos.wrapping_sub(self.last_ts) as i64;
+        self.last_ts = nanos;
+        write_varint(&mut self.file, ((delta << 1) ^ (delta >> 63)) as u64)
+    }
+
+    fn metadata(&mut self, metadata: serde_json::Value) -> io::Result<()> {
+        if metadata.is_null() {
+            write_bytes(&mut self.file, b"")
+        } else {
+            write_bytes(&mut self.file, &serde_json::to_vec(&metadata)?)
+        }
+    }
+
+    fn start(&mut self, tag: u8, name: String, id: SpanId, parent_id: SpanId, ts: Duration, metadata: serde_json::Value) -> io::Result<()> {
+        let name = self.string(name)?;
+        self.file.write_all(&[tag])?;
+        write_varint(&mut self.file, name)?;
+        self.span_ref(Some(parent_id))?;
+        self.new_span(id)?;
+        self.ts(ts)?;
+        self.metadata(metadata)
+    }
+
+    fn end(&mut self, tag: u8, id: SpanId, ts: Duration) -> io::Result<()> {
+        self.file.write_all(&[tag])?;
+        self.span_ref(Some(id))?;
+        self.spans.remove(&id);
+        self.ts(ts)
+    }
+
+    fn write_event(&mut self, event: TraceEvent) -> io::Result<()> {
+        match event {
+            TraceEvent::AsyncStart { name, id, parent_id, ts, metadata } => {
+                self.start(ASYNC_START, name, id, parent_id, ts, metadata)
+            },
+            TraceEvent::AsyncOnCPU { id, ts, thread_id } => {
+                self.file.write_all(&[ASYNC_ON_CPU])?;
+                self.span_ref(Some(id))?;
+                self.ts(ts)?;
+                self.span_ref(thread_id)
+            },
+            TraceEvent::AsyncOffCPU { id, ts } => {
+                self.file.write_all(&[ASYNC_OFF_CPU])?;
+                self.span_ref(Some(id))?;
+                self.ts(ts)
+            },
+            TraceEvent::AsyncEnd { id, ts, outcome } => {
+                self.end(ASYNC_END, id, ts)?;
+                match outcome {
+                    AsyncOutcome::Success => self.file.write_all(&[OUTCOME_SUCCESS]),
+                    AsyncOutcome::Cancelled => self.file.write_all(&[OUTCOME_CANCELLED]),
+                    AsyncOutcome::Error(e) => {
+                        self.file.write_all(&[OUTCOME_ERROR])?;
+                        write_bytes(&mut self.file, e.as_bytes())
+                    },
+                }
+            },
+            TraceEvent::SyncStart { name, id, parent_id, ts, metadata } => {
+                self.start(SYNC_START, name, id, parent_id, ts, metadata)
+            },
+            TraceEvent::SyncEnd { id, ts } => self.end(SYNC_END, id, ts),
+            TraceEvent::ThreadStart { name, id, ts } => {
+                let name = self.string(name)?;
+                self.file.write_all(&[THREAD_START])?;
+                write_varint(&mut self.file, name)?;
+                self.new_span(id)?;
+                self.ts(ts)
+            },
+            TraceEvent::ThreadEnd { id, ts } => self.end(THREAD_END, id, ts),
+            TraceEvent::Wakeup { waking_span, parked_span, ts } => {
+                self.file.write_all(&[WAKEUP])?;
+                self.span_ref(Some(waking_span))?;
+                self.span_ref(Some(parked_span))?;
+                self.ts(ts)
+            },
+        }
+    }
+}
+
+impl Logger for BinaryWriter {
+    fn write(&mut self, event: TraceEvent) {
+        self.write_event(event).expect("Failed to write to logfile");
+    }
+    fn flush(&mut self) {
+        self.file.flush().expect("Failed to flush");
+    }
+}
+
+/// Iterator over the events in a binary trace written by `BinaryWriter`.  Like the JSON loader, it
+/// stops quietly at a truncated final record, since the trace may still be being written.
+pub struct BinaryReader<R> {
+    reader: R,
+    strings: Vec<String>,
+    spans: Vec<SpanId>,
+    last_ts: u64,
+}
+
+impl<R: Read> BinaryReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = vec![0u8; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a binary trace"));
+        }
+        Ok(BinaryReader {
+            reader,
+            strings: Vec::new(),
+            spans: Vec::new(),
+            last_ts: 0,
+        })
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let index = read_varint(&mut self.reader)? as usize;
+        self.strings.get(index)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Undefined string"))
+    }
+
+    fn new_span(&mut self) -> io::Result<SpanId> {
+        let id = SpanId(read_varint(&mut self.reader)?);
+        self.spans.push(id);
+        Ok(id)
+    }
+
+    fn span_ref(&mut self) -> io::Result<Option<SpanId>> {
+        match read_varint(&mut self.reader)? {
+            REF_NONE => Ok(None),
+            REF_RAW => Ok(Some(SpanId(read_varint(&mut self.reader)?))),
+            index => self.spans.get((index - REF_INDEX) as usize)
+                .cloned()
+                .map(Some)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Undefined span")),
+        }
+    }
+
+    fn span(&mut self) -> io::Result<SpanId> {
+        self.span_ref()?.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Missing span"))
+    }
+
+    fn ts(&mut self) -> io::Result<Duration> {
+        let zigzag = read_varint(&mut self.reader)?;
+        let delta = ((zigzag >> 1) as i64) ^ -((zigzag & 1) as i64);
+        self.last_ts = self.last_ts.wrapping_add(delta as u64);
+        Ok(Duration::from_nanos(self.last_ts))
+    }
+
+    fn metadata(&mut self) -> io::Result<serde_json::Value> {
+        let bytes = read_bytes(&mut self.reader)?;
+        if bytes.is_empty() {
+            Ok(serde_json::Value::Null)
+        } else {
+            Ok(serde_json::from_slice(&bytes)?)
+        }
+    }
+
+    fn read_event(&mut self) -> io::Result<Option<TraceEvent>> {
+        loop {
+            let mut tag = [0u8];
+            if self.reader.read(&mut tag)? == 0 {
+                return Ok(None);
+            }
+            let event = match tag[0] {
+                STRING => {
+                    let s = read_string(&mut self.reader)?;
+                    self.strings.push(s);
+                    continue;
+                },
+                ASYNC_START => {
+                    let name = self.string()?;
+                    let parent_id = self.span()?;
+                    let id = self.new_span()?;
+                    let ts = self.ts()?;
+                    let metadata = self.metadata()?;
+                    TraceEvent::AsyncStart { name, id, parent_id, ts, metadata }
+                },
+                ASYNC_ON_CPU => {
+                    let id = self.span()?;
+                    let ts = self.ts()?;
+                    let thread_id = self.span_ref()?;
+                    TraceEvent::AsyncOnCPU { id, ts, thread_id }
+                },
+                ASYNC_OFF_CPU => {
+                    let id = self.span()?;
+                    let ts = self.ts()?;
+                    TraceEvent::AsyncOffCPU { id, ts }
+                },
+                ASYNC_END => {
+                    let id = self.span()?;
+                    let ts = self.ts()?;
+                    let mut outcome = [0u8];
+                    self.reader.read_exact(&mut outcome)?;
+                    let outcome = match outcome[0] {
+                        OUTCOME_SUCCESS => AsyncOutcome::Success,
+                        OUTCOME_CANCELLED => AsyncOutcome::Cancelled,
+                        OUTCOME_ERROR => AsyncOutcome::Error(read_string(&mut self.reader)?),
+                        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown outcome")),
+                    };
+                    TraceEvent::AsyncEnd { id, ts, outcome }
+                },
+                SYNC_START => {
+                    let name = self.string()?;
+                    let parent_id = self.span()?;
+                    let id = self.new_span()?;
+                    let ts = self.ts()?;
+                    let metadata = self.metadata()?;
+                    TraceEvent::SyncStart { name, id, parent_id, ts, metadata }
+                },
+                SYNC_END => {
+                    let id = self.span()?;
+                    let ts = self.ts()?;
+                    TraceEvent::SyncEnd { id, ts }
+                },
+                THREAD_START => {
+                    let name = self.string()?;
+                    let id = self.new_span()?;
+                    let ts = self.ts()?;
+                    TraceEvent::ThreadStart { name, id, ts }
+                },
+                THREAD_END => {
+                    let id = self.span()?;
+                    let ts = self.ts()?;
+                    TraceEvent::ThreadEnd { id, ts }
+                },
+                WAKEUP => {
+                    let waking_span = self.span()?;
+                    let parked_span = self.span()?;
+                    let ts = self.ts()?;
+                    TraceEvent::Wakeup { waking_span, parked_span, ts }
+                },
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown record")),
+            };
+            return Ok(Some(event));
+        }
+    }
+}
+
+impl<R: Read> Iterator for BinaryReader<R> {
+    type Item = io::Result<TraceEvent>;
+
+    fn next(&mut self) -> Option<io::Result<TraceEvent>> {
+        match self.read_event() {
+            Ok(event) => event.map(Ok),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::Duration;
+use serde_json;
+
+use event::{AsyncOutcome, SpanId, TraceEvent};
+use state::Logger;
+
+// We don't know which process wrote the trace when converting a file, so everything goes in one.
+const PID: u64 = 1;
+
+/// `Logger` that writes the Chrome Trace Event Format's JSON array form, which chrome://tracing and
+/// Perfetto can open.  Threads become `tid`s, sync spans become duration (`B`/`E`) events, async
+/// spans and their on-CPU slices become nested async (`b`/`e`) events, and wakeups become flow
+/// events from the waking span to the parked span's next poll.  Metadata goes in `args`.
+///
+/// The closing `]` is written on drop, but the format allows it to be missing if we never get
+/// there.
+pub struct ChromeWriter {
+    file: BufWriter<File>,
+    first: bool,
+    next_tid: u64,
+    // The thread each open span runs on, inherited from its parent for async spans.
+    tids: HashMap<SpanId, u64>,
+    // Names of open async spans, which we repeat on their `e` events.
+    names: HashMap<SpanId, String>,
+    next_flow: u64,
+    // Flows from wakeups that will end at the parked span's next poll.
+    pending_flows: HashMap<SpanId, Vec<u64>>,
+}
+
+fn micros(ts: Duration) -> f64 {
+    ts.as_secs() as f64 * 1e6 + ts.subsec_nanos() as f64 / 1e3
+}
+
+// `args` has to be an object, so wrap anything else.
+fn args(metadata: serde_json::Value) -> serde_json::Value {
+    match metadata {
+        serde_json::Value::Object(..) => metadata,
+        serde_json::Value::Null => json!({}),
+        metadata => json!({ "metadata": metadata }),
+    }
+}
+
+fn async_id(id: SpanId) -> String {
+    format!("{:#x}", id.0)
+}
+
+impl ChromeWriter {
+    pub fn new(f: File) -> Self {
+        let mut file = BufWriter::new(f);
+        file.write_all(b"[\n").expect("Failed to write to logfile");
+        ChromeWriter {
+            file,
+            first: true,
+            next_tid: 1,
+            tids: HashMap::new(),
+            names: HashMap::new(),
+            next_flow: 0,
+            pending_flows: HashMap::new(),
+        }
+    }
+
+    fn emit(&mut self, value: serde_json::Value) {
+        if !self.first {
+            self.file.write_all(b",\n").expect("Failed to write to logfile");
+        }
+        self.first = false;
+        serde_json::to_writer(&mut self.file, &value).expect("Failed to write to logfile");
+    }
+
+    fn tid(&self, id: SpanId) -> u64 {
+        self.tids.get(&id).cloned().unwrap_or(0)
+    }
+}
+
+impl Logger for ChromeWriter {
+    fn write(&mut self, event: TraceEvent) {
+        match event {
+            TraceEvent::ThreadStart { name, id, ts } => {
+                let tid = self.next_tid;
+                self.next_tid += 1;
+                self.tids.insert(id, tid);
+                self.emit(json!({
+                    "ph": "M", "name": "thread_name", "pid": PID, "tid": tid,
+                    "args": { "name": name },
+                }));
+                self.emit(json!({
+                    "ph": "B", "name": name, "ts": micros(ts), "pid": PID, "tid": tid,
+                }));
+            },
+            TraceEvent::ThreadEnd { id, ts } => {
+                let tid = self.tid(id);
+                self.emit(json!({ "ph": "E", "ts": micros(ts), "pid": PID, "tid": tid }));
+                self.tids.remove(&id);
+            },
+            TraceEvent::SyncStart { name, id, parent_id, ts, metadata } => {
+                let tid = self.tid(parent_id);
+                self.tids.insert(id, tid);
+                self.emit(json!({
+                    "ph": "B", "name": name, "ts": micros(ts), "pid": PID, "tid": tid,
+                    "args": args(metadata),
+                }));
+            },
+            TraceEvent::SyncEnd { id, ts } => {
+                let tid = self.tid(id);
+                self.emit(json!({ "ph": "E", "ts": micros(ts), "pid": PID, "tid": tid }));
+                self.tids.remove(&id);
+            },
+            TraceEvent::AsyncStart { name, id, parent_id, ts, metadata } => {
+                let tid = self.tid(parent_id);
+                self.tids.insert(id, tid);
+                self.emit(json!({
+                    "ph": "b", "cat": "async", "name": name, "id": async_id(id),
+                    "ts": micros(ts), "pid": PID, "tid": tid, "args": args(metadata),
+                }));
+                self.names.insert(id, name);
+            },
+            TraceEvent::AsyncOnCPU { id, ts, thread_id } => {
+                let tid = match thread_id {
+                    Some(thread_id) => self.tid(thread_id),
+                    None => self.tid(id),
+                };
+                let name = self.names.get(&id).cloned().unwrap_or_default();
+                for flow in self.pending_flows.remove(&id).unwrap_or_default() {
+                    self.emit(json!({
+                        "ph": "f", "bp": "e", "cat": "wakeup", "name": "wakeup", "id": flow,
+                        "ts": micros(ts), "pid": PID, "tid": tid,
+                    }));
+                }
+                self.emit(json!({
+                    "ph": "b", "cat": "async", "name": name, "id": async_id(id),
+                    "ts": micros(ts), "pid": PID, "tid": tid, "args": { "on_cpu": true },
+                }));
+            },
+            TraceEvent::AsyncOffCPU { id, ts } => {
+                let tid = self.tid(id);
+                let name = self.names.get(&id).cloned().unwrap_or_default();
+                self.emit(json!({
+                    "ph": "e", "cat": "async", "name": name, "id": async_id(id),
+                    "ts": micros(ts), "pid": PID, "tid": tid,
+                }));
+            },
+            TraceEvent::AsyncEnd { id, ts, outcome } => {
+                let tid = self.tid(id);
+                let name = self.names.remove(&id).unwrap_or_default();
+                let outcome = match outcome {
+                    AsyncOutcome::Success => json!("success"),
+                    AsyncOutcome::Cancelled => json!("cancelled"),
+                    AsyncOutcome::Error(e) => json!({ "error": e }),
+                };
+                self.emit(json!({
+                    "ph": "e", "cat": "async", "name": name, "id": async_id(id),
+                    "ts": micros(ts), "pid": PID, "tid": tid, "args": { "outcome": outcome },
+                }));
+                self.tids.remove(&id);
+                self.pending_flows.remove(&id);
+            },
+            TraceEvent::Wakeup { waking_span, parked_span, ts } => {
+                let flow = self.next_flow;
+                self.next_flow += 1;
+                let tid = self.tid(waking_span);
+                self.emit(json!({
+                    "ph": "s", "cat": "wakeup", "name": "wakeup", "id": flow,
+                    "ts": micros(ts), "pid": PID, "tid": tid,
+                }));
+                self.pending_flows.entry(parked_span).or_default().push(flow);
+            },
+        }
+    }
+
+    fn flush(&mut self) {
+        self.file.flush().expect("Failed to flush");
+    }
+}
+
+impl Drop for ChromeWriter {
+    fn drop(&mut self) {
+        let _ = self.file.write_all(b"\n]\n");
+        let _ = self.file.flush();
+    }
+}
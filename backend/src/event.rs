@@ -11,14 +11,14 @@ impl SpanId {
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum AsyncOutcome {
     Success,
     Cancelled,
     Error(String),
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum TraceEvent {
     AsyncStart {
         name: String,
@@ -31,6 +31,10 @@ pub enum TraceEvent {
     AsyncOnCPU {
         id: SpanId,
         ts: Duration,
+        // The `ThreadStart` span of the thread doing the polling, since a future may move between
+        // threads over its lifetime.
+        #[serde(default)]
+        thread_id: Option<SpanId>,
     },
     AsyncOffCPU {
         id: SpanId,
@@ -71,3 +75,19 @@ pub enum TraceEvent {
         ts: Duration,
     },
 }
+
+impl TraceEvent {
+    pub fn ts(&self) -> Duration {
+        match *self {
+            TraceEvent::AsyncStart { ts, .. } |
+            TraceEvent::AsyncOnCPU { ts, .. } |
+            TraceEvent::AsyncOffCPU { ts, .. } |
+            TraceEvent::AsyncEnd { ts, .. } |
+            TraceEvent::SyncStart { ts, .. } |
+            TraceEvent::SyncEnd { ts, .. } |
+            TraceEvent::ThreadStart { ts, .. } |
+            TraceEvent::ThreadEnd { ts, .. } |
+            TraceEvent::Wakeup { ts, .. } => ts,
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use serde_json;
+use tracing_core::{Metadata, Subscriber};
+use tracing_core::field::{Field, Visit};
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use event::{AsyncOutcome, SpanId, TraceEvent};
+use state::{Logger, TRACER_STATE, since_epoch};
+
+thread_local! {
+    // The span that was current before each `on_enter` on this thread, restored by `on_exit`.
+    static ENTERED: RefCell<Vec<Option<SpanId>>> = const { RefCell::new(Vec::new()) };
+    // Our own `ThreadStart` for threads that aren't inside a `TracedThread`, ended when the
+    // thread exits.
+    static THREAD: RefCell<Option<ThreadGuard>> = const { RefCell::new(None) };
+}
+
+struct ThreadGuard {
+    id: SpanId,
+    logger: Arc<Mutex<dyn Logger>>,
+}
+
+impl Drop for ThreadGuard {
+    fn drop(&mut self) {
+        if let Ok(mut logger) = self.logger.lock() {
+            logger.write(TraceEvent::ThreadEnd { id: self.id, ts: since_epoch() });
+        }
+    }
+}
+
+/// `tracing_subscriber::Layer` that records `tracing` spans as cyclotron spans.  Spans are async
+/// by default: creating one emits `AsyncStart`, entering and exiting it emit `AsyncOnCPU` and
+/// `AsyncOffCPU`, and closing it emits `AsyncEnd`.  Its fields are recorded as its metadata.
+///
+/// Entering a span also makes it the thread's current span, so `TraceFuture`s and `SyncSpan`s
+/// created inside of it become its children.  Spans on threads outside of a `TracedThread` are
+/// put in a thread of their own, which ends when the thread exits.
+pub struct TraceLayer<L> {
+    logger: Arc<Mutex<L>>,
+    is_sync: fn(&Metadata) -> bool,
+}
+
+impl<L: Logger + 'static> TraceLayer<L> {
+    pub fn new(logger: L) -> Self {
+        TraceLayer {
+            logger: Arc::new(Mutex::new(logger)),
+            is_sync: |_| false,
+        }
+    }
+
+    /// Record spans matching `is_sync` as `SyncStart` on their first enter and `SyncEnd` on close
+    /// instead of as async spans.
+    pub fn sync_spans(mut self, is_sync: fn(&Metadata) -> bool) -> Self {
+        self.is_sync = is_sync;
+        self
+    }
+
+    fn emit(&self, event: TraceEvent) {
+        self.logger.lock().unwrap().write(event);
+    }
+
+    fn thread(&self) -> SpanId {
+        if let Some(thread) = TRACER_STATE.with(|c| c.borrow().thread) {
+            return thread;
+        }
+        THREAD.with(|t| {
+            if let Some(ref guard) = *t.borrow() {
+                return guard.id;
+            }
+            let current = thread::current();
+            let id = SpanId::new();
+            self.emit(TraceEvent::ThreadStart {
+                name: current.name()
+                    .map(String::from)
+                    .unwrap_or_else(|| format!("{:?}", current.id())),
+                id,
+                ts: since_epoch(),
+            });
+            *t.borrow_mut() = Some(ThreadGuard { id, logger: self.logger.clone() });
+            id
+        })
+    }
+
+    fn start(&self, name: &str, state: &mut SpanState) {
+        let metadata = serde_json::Value::Object(mem::replace(&mut state.fields, serde_json::Map::new()));
+        let event = if state.is_sync {
+            TraceEvent::SyncStart {
+                name: name.to_owned(),
+                id: state.id,
+                parent_id: state.parent,
+                ts: since_epoch(),
+                metadata,
+            }
+        } else {
+            TraceEvent::AsyncStart {
+                name: name.to_owned(),
+                id: state.id,
+                parent_id: state.parent,
+                ts: since_epoch(),
+                metadata,
+            }
+        };
+        self.emit(event);
+        state.started = true;
+    }
+}
+
+struct SpanState {
+    id: SpanId,
+    parent: SpanId,
+    is_sync: bool,
+    started: bool,
+    // Fields recorded before the span's start event was emitted.
+    fields: serde_json::Map<String, serde_json::Value>,
+}
+
+struct FieldVisitor<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'a> Visit for FieldVisitor<'a> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.insert(field.name().to_owned(), format!("{:?}", value).into());
+    }
+}
+
+impl<S, L> Layer<S> for TraceLayer<L>
+    where S: Subscriber + for<'a> LookupSpan<'a>,
+          L: Logger + 'static,
+{
+    fn on_new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<S>) {
+        let span = ctx.span(id).expect("Missing span");
+        // Sync spans only start when they're first entered, so a parent may not have a start in
+        // the trace yet, and then the thread stands in for it.
+        let parent = match span.parent() {
+            Some(parent) => parent.extensions().get::<SpanState>().filter(|s| s.started).map(|s| s.id),
+            None => TRACER_STATE.with(|c| c.borrow().current_span),
+        };
+        let parent = parent.unwrap_or_else(|| self.thread());
+
+        let mut state = SpanState {
+            id: SpanId::new(),
+            parent,
+            is_sync: (self.is_sync)(attrs.metadata()),
+            started: false,
+            fields: serde_json::Map::new(),
+        };
+        attrs.record(&mut FieldVisitor(&mut state.fields));
+        if !state.is_sync {
+            self.start(span.name(), &mut state);
+        }
+        span.extensions_mut().insert(state);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record, ctx: Context<S>) {
+        let span = ctx.span(id).expect("Missing span");
+        let mut extensions = span.extensions_mut();
+        if let Some(state) = extensions.get_mut::<SpanState>() {
+            if !state.started {
+                values.record(&mut FieldVisitor(&mut state.fields));
+            }
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<S>) {
+        let span = ctx.span(id).expect("Missing span");
+        let mut extensions = span.extensions_mut();
+        let state = match extensions.get_mut::<SpanState>() {
+            Some(state) => state,
+            None => return,
+        };
+        if !state.started {
+            self.start(span.name(), state);
+        }
+        if !state.is_sync {
+            self.emit(TraceEvent::AsyncOnCPU {
+                id: state.id,
+                ts: since_epoch(),
+                thread_id: Some(self.thread()),
+            });
+        }
+        let previous = TRACER_STATE.with(|c| {
+            c.borrow_mut().current_span.replace(state.id)
+        });
+        ENTERED.with(|e| e.borrow_mut().push(previous));
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<S>) {
+        let span = ctx.span(id).expect("Missing span");
+        let extensions = span.extensions();
+        let state = match extensions.get::<SpanState>() {
+            Some(state) => state,
+            None => return,
+        };
+        if !state.is_sync {
+            self.emit(TraceEvent::AsyncOffCPU {
+                id: state.id,
+                ts: since_epoch(),
+            });
+        }
+        if let Some(previous) = ENTERED.with(|e| e.borrow_mut().pop()) {
+            TRACER_STATE.with(|c| c.borrow_mut().current_span = previous);
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<S>) {
+        let span = ctx.span(&id).expect("Missing span");
+        let extensions = span.extensions();
+        let state = match extensions.get::<SpanState>() {
+            Some(state) if state.started => state,
+            _ => return,
+        };
+        let event = if state.is_sync {
+            TraceEvent::SyncEnd {
+                id: state.id,
+                ts: since_epoch(),
+            }
+        } else {
+            TraceEvent::AsyncEnd {
+                id: state.id,
+                ts: since_epoch(),
+                outcome: AsyncOutcome::Success,
+            }
+        };
+        self.emit(event);
+    }
+}
@@ -1,20 +1,37 @@
+extern crate crossbeam_queue;
 extern crate futures;
 extern crate rand;
 extern crate serde;
+#[macro_use]
 extern crate serde_json;
+#[cfg(unix)]
+extern crate signal_hook;
+extern crate tracing_core;
+extern crate tracing_subscriber;
 #[macro_use]
 extern crate lazy_static;
 #[allow(unused_imports)]
 #[macro_use]
 extern crate serde_derive;
+#[cfg(test)]
+extern crate futures03;
+#[cfg(test)]
+extern crate tracing;
 
 mod async;
 mod event;
 mod state;
+mod std_future;
 mod sync;
+pub mod background;
+pub mod binary;
+pub mod chrome;
 pub mod json;
+pub mod layer;
+pub mod recorder;
 
 pub use async::{TraceFuture, TracedFuture};
+pub use std_future::{TraceStdFuture, TracedStdFuture};
 pub use event::{TraceEvent, SpanId, AsyncOutcome};
 pub use sync::{TracedThread, SyncSpan};
 pub use state::{DebugLogger, NoopLogger, Logger};
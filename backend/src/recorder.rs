@@ -0,0 +1,214 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::thread;
+use std::time::Duration;
+
+use event::{SpanId, TraceEvent};
+use json::JsonWriter;
+use state::Logger;
+
+// How long a panic waits for another thread to finish logging before giving up on the dump.
+const PANIC_LOCK_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How much history a `FlightRecorder` keeps.
+#[derive(Clone, Copy, Debug)]
+pub enum Retention {
+    /// The most recent `n` events.
+    Count(usize),
+    /// Events within this long of the most recent one.
+    Window(Duration),
+}
+
+struct Ring {
+    retention: Retention,
+    events: VecDeque<TraceEvent>,
+    // Spans that have started but not yet ended.
+    open: HashSet<SpanId>,
+    // Start events that fell out of `events` for spans that haven't ended yet.  We need these to
+    // make sense of the span's later events when dumping.
+    evicted_starts: HashMap<SpanId, TraceEvent>,
+}
+
+impl Ring {
+    fn push(&mut self, event: TraceEvent) {
+        match event {
+            TraceEvent::AsyncStart { id, .. } |
+            TraceEvent::SyncStart { id, .. } |
+            TraceEvent::ThreadStart { id, .. } => {
+                self.open.insert(id);
+            },
+            TraceEvent::AsyncEnd { id, .. } |
+            TraceEvent::SyncEnd { id, .. } |
+            TraceEvent::ThreadEnd { id, .. } => {
+                self.open.remove(&id);
+                self.evicted_starts.remove(&id);
+            },
+            _ => (),
+        }
+        let newest = event.ts();
+        self.events.push_back(event);
+
+        loop {
+            let evict = match (self.retention, self.events.front()) {
+                (Retention::Count(n), Some(_)) => self.events.len() > n,
+                (Retention::Window(window), Some(oldest)) => oldest.ts() + window < newest,
+                (_, None) => false,
+            };
+            if !evict {
+                break;
+            }
+            let event = self.events.pop_front().unwrap();
+            match event {
+                TraceEvent::AsyncStart { id, .. } |
+                TraceEvent::SyncStart { id, .. } |
+                TraceEvent::ThreadStart { id, .. } if self.open.contains(&id) => {
+                    self.evicted_starts.insert(id, event);
+                },
+                _ => (),
+            }
+        }
+    }
+
+    // Write out the retained events, preceded by the starts of spans that are still open, and skip
+    // any event that refers to a span whose start we no longer have.  This keeps the dump loadable
+    // even though it begins in the middle of the trace.
+    fn dump(&self, writer: &mut JsonWriter) {
+        let mut evicted_starts = self.evicted_starts.values().collect::<Vec<_>>();
+        evicted_starts.sort_by_key(|e| e.ts());
+
+        let mut known = HashSet::new();
+        let mut on_cpu = HashSet::new();
+        for event in evicted_starts.into_iter().chain(self.events.iter()) {
+            let keep = match *event {
+                TraceEvent::AsyncStart { id, parent_id, .. } |
+                TraceEvent::SyncStart { id, parent_id, .. } => {
+                    known.contains(&parent_id) && known.insert(id)
+                },
+                TraceEvent::ThreadStart { id, .. } => known.insert(id),
+                TraceEvent::AsyncOnCPU { id, .. } => known.contains(&id) && on_cpu.insert(id),
+                TraceEvent::AsyncOffCPU { id, .. } => on_cpu.remove(&id),
+                TraceEvent::AsyncEnd { id, .. } |
+                TraceEvent::SyncEnd { id, .. } |
+                TraceEvent::ThreadEnd { id, .. } => known.contains(&id),
+                TraceEvent::Wakeup { waking_span, parked_span, .. } => {
+                    known.contains(&waking_span) && known.contains(&parked_span)
+                },
+            };
+            if !keep {
+                continue;
+            }
+            let event = match *event {
+                TraceEvent::AsyncOnCPU { id, ts, thread_id } => TraceEvent::AsyncOnCPU {
+                    id,
+                    ts,
+                    thread_id: thread_id.filter(|t| known.contains(t)),
+                },
+                ref event => event.clone(),
+            };
+            writer.write(event);
+        }
+        writer.flush();
+    }
+}
+
+/// `Logger` that keeps only recent history in memory and writes it out as a regular JSON trace
+/// when asked to with `dump`, when the process panics (`dump_on_panic`) or when it receives a
+/// signal (`dump_on_signal`).
+///
+/// Clones share the same ring, so hand one to each `TracedThread`.
+#[derive(Clone)]
+pub struct FlightRecorder {
+    ring: Arc<Mutex<Ring>>,
+}
+
+impl FlightRecorder {
+    pub fn new(retention: Retention) -> Self {
+        FlightRecorder {
+            ring: Arc::new(Mutex::new(Ring {
+                retention,
+                events: VecDeque::new(),
+                open: HashSet::new(),
+                evicted_starts: HashMap::new(),
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Ring> {
+        // A panic while holding the lock can't leave the ring in a state that's unsafe to dump.
+        self.ring.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Try to take the lock, backing off between attempts, for at most about `timeout`.
+    fn try_lock_for(&self, timeout: Duration) -> Option<MutexGuard<'_, Ring>> {
+        let mut waited = Duration::from_millis(0);
+        let mut backoff = Duration::from_micros(10);
+        loop {
+            match self.ring.try_lock() {
+                Ok(ring) => return Some(ring),
+                Err(TryLockError::Poisoned(e)) => return Some(e.into_inner()),
+                Err(TryLockError::WouldBlock) if waited >= timeout => return None,
+                Err(TryLockError::WouldBlock) => {
+                    thread::sleep(backoff);
+                    waited += backoff;
+                    backoff = cmp::min(backoff * 2, Duration::from_millis(10));
+                },
+            }
+        }
+    }
+
+    pub fn dump<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = JsonWriter::new(File::create(path)?);
+        self.lock().dump(&mut writer);
+        Ok(())
+    }
+
+    /// Dump to `path` whenever a thread panics, before running the existing panic hook.
+    pub fn dump_on_panic<P: Into<PathBuf>>(&self, path: P) {
+        let path = path.into();
+        let recorder = self.clone();
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            // Another thread only holds the lock while it logs an event, so wait a little for it.
+            // If it's still held after that, it's most likely the panicking thread that holds
+            // it, in the middle of logging, so skip the dump rather than deadlocking on ourselves.
+            match recorder.try_lock_for(PANIC_LOCK_TIMEOUT) {
+                Some(ring) => match File::create(&path) {
+                    Ok(f) => ring.dump(&mut JsonWriter::new(f)),
+                    Err(e) => eprintln!("Failed to dump flight recorder to {:?}: {}", path, e),
+                },
+                None => eprintln!("Flight recorder is busy, not dumping it to {:?}", path),
+            }
+            previous(info);
+        }));
+    }
+
+    /// Dump to `path` every time the process receives `signal` (e.g. `SIGUSR1`), from a
+    /// background thread.
+    #[cfg(unix)]
+    pub fn dump_on_signal<P: Into<PathBuf>>(&self, signal: i32, path: P) -> io::Result<()> {
+        let path = path.into();
+        let recorder = self.clone();
+        let mut signals = ::signal_hook::iterator::Signals::new([signal])?;
+        ::std::thread::Builder::new()
+            .name("cyclotron-recorder".into())
+            .spawn(move || {
+                for _ in signals.forever() {
+                    if let Err(e) = recorder.dump(&path) {
+                        eprintln!("Failed to dump flight recorder to {:?}: {}", path, e);
+                    }
+                }
+            })?;
+        Ok(())
+    }
+}
+
+impl Logger for FlightRecorder {
+    fn write(&mut self, event: TraceEvent) {
+        self.lock().push(event);
+    }
+}
@@ -11,6 +11,13 @@ lazy_static! {
     static ref EPOCH: (SystemTime, Instant) = (SystemTime::now(), Instant::now());
 }
 
+/// Time since the tracing epoch, which every event's `ts` is relative to.  Useful for emitting
+/// events from outside of a `TracedThread`.
+pub fn since_epoch() -> Duration {
+    let (_, epoch) = *EPOCH;
+    Instant::now().duration_since(epoch)
+}
+
 pub trait Logger: Send {
     fn write(&mut self, event: TraceEvent);
     fn flush(&mut self) {
@@ -41,6 +48,7 @@ impl Logger for NoopLogger {
 }
 
 pub struct TracerState {
+    pub thread: Option<SpanId>,
     pub current_span: Option<SpanId>,
     pub currently_logging_wakeup: bool,
 
@@ -55,6 +63,7 @@ impl Default for TracerState {
         let (_, epoch) = *EPOCH;
         let now = Instant::now();
         TracerState {
+            thread: None,
             current_span: None,
             currently_logging_wakeup: false,
             writer: None,
@@ -0,0 +1,98 @@
+use std::future::Future;
+use std::ops::{
+    Deref,
+    DerefMut,
+};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{
+    Context,
+    Poll,
+    Wake,
+    Waker,
+};
+use serde_json;
+use async::{TraceState, log_wakeup};
+use event::{AsyncOutcome, SpanId};
+use state::TRACER_STATE;
+
+/// `TraceFuture` for `std::future::Future`s.  Since there's no error type, a traced future that
+/// completes always ends with `AsyncOutcome::Success`.
+pub trait TraceStdFuture: Future + Sized {
+    fn traced<S: Into<String>>(self, name: S) -> TracedStdFuture<Self> {
+        self.with_metadata(name, serde_json::Value::Null)
+    }
+
+    fn with_metadata<S: Into<String>>(self, name: S, meta: serde_json::Value) -> TracedStdFuture<Self> {
+        TracedStdFuture {
+            state: TraceState::new(name.into(), meta),
+            inner: self,
+        }
+    }
+}
+impl<F: Future + Sized> TraceStdFuture for F {}
+
+pub struct TracedStdFuture<F> {
+    state: TraceState,
+    inner: F,
+}
+
+impl<F> Deref for TracedStdFuture<F> {
+    type Target = F;
+    fn deref(&self) -> &F {
+        &self.inner
+    }
+}
+
+impl<F: Unpin> DerefMut for TracedStdFuture<F> {
+    fn deref_mut(&mut self) -> &mut F {
+        &mut self.inner
+    }
+}
+
+impl<F> TracedStdFuture<F> {
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+}
+
+impl<F: Future> Future for TracedStdFuture<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+        // `inner` is structurally pinned, but `state` isn't, and we never move out of `inner`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let inner = unsafe { Pin::new_unchecked(&mut this.inner) };
+        let state = &mut this.state;
+
+        TRACER_STATE.with(|c| {
+            let (previous, span_id) = state.begin_poll(&mut c.borrow_mut());
+
+            let notifier = Arc::new(Notifier { parent_waker: cx.waker().clone(), parked_span: span_id });
+            let waker = Waker::from(notifier);
+            let result = inner.poll(&mut Context::from_waker(&waker));
+
+            let outcome = match result {
+                Poll::Ready(..) => Some(AsyncOutcome::Success),
+                Poll::Pending => None,
+            };
+            state.end_poll(&mut c.borrow_mut(), previous, outcome);
+            result
+        })
+    }
+}
+
+struct Notifier {
+    parent_waker: Waker,
+    parked_span: SpanId,
+}
+
+impl Wake for Notifier {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        log_wakeup(self.parked_span, || self.parent_waker.wake_by_ref());
+    }
+}
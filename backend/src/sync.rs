@@ -14,6 +14,7 @@ impl TracedThread {
             let span_id = SpanId::new();
 
             assert!(st.current_span.is_none());
+            st.thread = Some(span_id);
             st.current_span = Some(span_id);
 
             let event = TraceEvent::ThreadStart {
@@ -32,6 +33,7 @@ impl Drop for TracedThread {
     fn drop(&mut self) {
         TRACER_STATE.with(|c| {
             let mut st = c.borrow_mut();
+            st.thread = None;
             st.current_span = None;
 
             let event = TraceEvent::ThreadEnd {
@@ -10,6 +10,7 @@ use std::sync::{Arc, Mutex};
 use futures::sync::oneshot;
 use futures::stream::futures_unordered::FuturesUnordered;
 use state::Logger;
+use event::{AsyncOutcome, SpanId, TraceEvent};
 use ::{
     DebugLogger,
     TracedThread,
@@ -66,3 +67,352 @@ fn test_async() {
 
     logger.flush();
 }
+
+#[test]
+fn test_std_async() {
+    use futures03::channel::oneshot;
+    use futures03::executor::block_on;
+    use futures03::future::{self, join_all, FutureExt};
+    use TraceStdFuture;
+
+    let mut logger = Arc::new(Mutex::new(JsonWriter::new(File::create("/tmp/test_std.log").unwrap())));
+
+    let _thread = TracedThread::new("test_std_async", Box::new(logger.clone()));
+
+    let (txs, rxs) = (0..10).map(|_| oneshot::channel::<usize>())
+        .unzip::<_, _, Vec<_>, Vec<_>>();
+
+    let rx_join = join_all(rxs.into_iter().enumerate().map(|(i, rx)| {
+        rx.map(Result::unwrap).traced(format!("rx:{}", i))
+    }));
+    let ready = future::ready(10).traced("ready");
+
+    let logger_ = logger.clone();
+    let sender = thread::spawn(move || {
+        let _thread = TracedThread::new("test_std_async:sender", Box::new(logger_));
+        thread::sleep(Duration::from_millis(10));
+        for (i, tx) in txs.into_iter().enumerate() {
+            tx.send(i).unwrap();
+        }
+    });
+
+    let (oneshots, ready) = block_on(future::join(rx_join, ready).traced("join"));
+    sender.join().unwrap();
+    assert_eq!(oneshots.iter().sum::<usize>() + ready, 55);
+
+    logger.flush();
+}
+
+impl Logger for Vec<TraceEvent> {
+    fn write(&mut self, event: TraceEvent) {
+        self.push(event);
+    }
+}
+
+#[test]
+fn test_cancelled() {
+    use futures03::channel::oneshot;
+    use futures03::FutureExt;
+    use TraceStdFuture;
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let _thread = TracedThread::new("test_cancelled", Box::new(events.clone()));
+
+    let (_tx, rx) = oneshot::channel::<usize>();
+    assert!(rx.traced("rx").now_or_never().is_none());
+
+    let events = events.lock().unwrap();
+    match events.last() {
+        Some(&TraceEvent::AsyncEnd { outcome: AsyncOutcome::Cancelled, .. }) => (),
+        e => panic!("Expected cancellation, found {:?}", e),
+    }
+}
+
+#[test]
+fn test_migration() {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use futures03::channel::oneshot;
+    use futures03::task::noop_waker;
+    use TraceStdFuture;
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let waker = noop_waker();
+
+    let (tx, rx) = oneshot::channel::<usize>();
+    let mut rx = {
+        let _thread = TracedThread::new("test_migration:a", Box::new(events.clone()));
+        let mut rx = rx.traced("rx");
+        assert!(Pin::new(&mut rx).poll(&mut Context::from_waker(&waker)).is_pending());
+        rx
+    };
+
+    let events_ = events.clone();
+    thread::spawn(move || {
+        let _thread = TracedThread::new("test_migration:b", Box::new(events_));
+        tx.send(1).unwrap();
+        match Pin::new(&mut rx).poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(Ok(1)) => (),
+            _ => panic!("Expected the oneshot to resolve"),
+        }
+    }).join().unwrap();
+
+    let events = events.lock().unwrap();
+    let threads = events.iter().filter_map(|e| match *e {
+        TraceEvent::ThreadStart { id, .. } => Some(id),
+        _ => None,
+    }).collect::<Vec<_>>();
+    let parent = events.iter().filter_map(|e| match *e {
+        TraceEvent::AsyncStart { parent_id, .. } => Some(parent_id),
+        _ => None,
+    }).collect::<Vec<_>>();
+    let polled_on = events.iter().filter_map(|e| match *e {
+        TraceEvent::AsyncOnCPU { thread_id, .. } => thread_id,
+        _ => None,
+    }).collect::<Vec<_>>();
+    assert_eq!(parent, vec![threads[0]]);
+    assert_eq!(polled_on, threads);
+}
+
+#[test]
+fn test_tracing_layer() {
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::Registry;
+    use layer::TraceLayer;
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let subscriber = Registry::default().with(TraceLayer::new(events.clone()));
+
+    let _thread = TracedThread::new("test_tracing_layer", Box::new(events.clone()));
+    tracing::subscriber::with_default(subscriber, || {
+        let outer = tracing::info_span!("outer", path = "/foo");
+        let _outer = outer.enter();
+        let _inner = SyncSpan::new("inner");
+    });
+
+    let events = events.lock().unwrap();
+    let (outer_id, metadata) = events.iter().filter_map(|e| match *e {
+        TraceEvent::AsyncStart { ref name, id, ref metadata, .. } if name == "outer" => Some((id, metadata)),
+        _ => None,
+    }).next().expect("Expected outer's start");
+    assert_eq!(metadata["path"], "/foo");
+    let inner_parent = events.iter().filter_map(|e| match *e {
+        TraceEvent::SyncStart { ref name, parent_id, .. } if name == "inner" => Some(parent_id),
+        _ => None,
+    }).collect::<Vec<_>>();
+    assert_eq!(inner_parent, vec![outer_id]);
+    match events.last() {
+        Some(&TraceEvent::AsyncEnd { id, .. }) => assert_eq!(id, outer_id),
+        e => panic!("Expected outer's end, found {:?}", e),
+    }
+}
+
+#[test]
+fn test_tracing_layer_threads() {
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::Registry;
+    use layer::TraceLayer;
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let layer = TraceLayer::new(events.clone()).sync_spans(|m| m.name() == "sync");
+    let subscriber = Registry::default().with(layer);
+
+    thread::spawn(move || {
+        tracing::subscriber::with_default(subscriber, || {
+            // `sync` hasn't been entered, so it hasn't started and can't be `child`'s parent.
+            let sync = tracing::info_span!("sync");
+            let child = tracing::info_span!(parent: &sync, "child");
+            let _child = child.enter();
+        });
+    }).join().unwrap();
+
+    let events = events.lock().unwrap();
+    let threads = events.iter().filter_map(|e| match *e {
+        TraceEvent::ThreadStart { id, .. } => Some(id),
+        _ => None,
+    }).collect::<Vec<_>>();
+    let ended = events.iter().filter_map(|e| match *e {
+        TraceEvent::ThreadEnd { id, .. } => Some(id),
+        _ => None,
+    }).collect::<Vec<_>>();
+    let parents = events.iter().filter_map(|e| match *e {
+        TraceEvent::AsyncStart { ref name, parent_id, .. } if name == "child" => Some(parent_id),
+        _ => None,
+    }).collect::<Vec<_>>();
+    assert_eq!(threads.len(), 1);
+    assert_eq!(ended, threads);
+    assert_eq!(parents, threads);
+    assert!(!events.iter().any(|e| matches!(*e, TraceEvent::SyncStart { .. })));
+    match events.last() {
+        Some(&TraceEvent::ThreadEnd { .. }) => (),
+        e => panic!("Expected the thread's end, found {:?}", e),
+    }
+}
+
+#[test]
+fn test_background() {
+    use background::BackgroundWriter;
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let writer = BackgroundWriter::new(events.clone(), 1024);
+
+    let threads = (0..4).map(|i| {
+        let logger = writer.logger();
+        thread::spawn(move || {
+            let _thread = TracedThread::new(format!("test_background:{}", i), Box::new(logger));
+            for _ in 0..10 {
+                let _span = SyncSpan::new("span");
+            }
+        })
+    }).collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let mut late = writer.logger();
+    drop(writer);
+    assert_eq!(events.lock().unwrap().len(), 4 * (2 + 2 * 10));
+
+    late.write(TraceEvent::ThreadEnd { id: SpanId(0), ts: Duration::from_secs(0) });
+    assert_eq!(late.dropped(), 1);
+}
+
+#[test]
+fn test_background_short_lived() {
+    use background::BackgroundWriter;
+
+    // Loggers dropped with events still in their queues don't lose them.
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let writer = BackgroundWriter::new(events.clone(), 16);
+    for i in 0..100 {
+        let mut logger = writer.logger();
+        logger.write(TraceEvent::ThreadEnd { id: SpanId(i), ts: Duration::from_secs(0) });
+    }
+    drop(writer);
+    assert_eq!(events.lock().unwrap().len(), 100);
+}
+
+#[test]
+fn test_background_flush() {
+    use background::BackgroundWriter;
+
+    // Flushing waits for the writer, and a zero capacity still leaves room for an event.
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let writer = BackgroundWriter::new(events.clone(), 0);
+    let mut logger = writer.logger();
+    logger.write(TraceEvent::ThreadEnd { id: SpanId(0), ts: Duration::from_secs(0) });
+    logger.flush();
+    assert_eq!(events.lock().unwrap().len(), 1);
+    assert_eq!(logger.dropped(), 0);
+
+    // Once the writer's gone there's nothing to wait for.
+    drop(writer);
+    logger.flush();
+}
+
+#[test]
+fn test_flight_recorder() {
+    use std::io::{BufRead, BufReader};
+    use serde_json;
+    use recorder::{FlightRecorder, Retention};
+
+    let recorder = FlightRecorder::new(Retention::Count(5));
+    {
+        let _thread = TracedThread::new("test_flight_recorder", Box::new(recorder.clone()));
+        for _ in 0..10 {
+            let _span = SyncSpan::new("span");
+        }
+        recorder.dump("/tmp/test_flight_recorder.log").unwrap();
+    }
+
+    let f = BufReader::new(File::open("/tmp/test_flight_recorder.log").unwrap());
+    let events = f.lines()
+        .map(|l| serde_json::from_str::<TraceEvent>(&l.unwrap()).unwrap())
+        .collect::<Vec<_>>();
+
+    // The thread's start was evicted but is still open, and the oldest retained event was the end
+    // of a span whose start was evicted.
+    assert_eq!(events.len(), 5);
+    match events[0] {
+        TraceEvent::ThreadStart { .. } => (),
+        ref e => panic!("Expected the thread's start, found {:?}", e),
+    }
+    match events[1] {
+        TraceEvent::SyncStart { .. } => (),
+        ref e => panic!("Expected a span's start, found {:?}", e),
+    }
+}
+
+#[test]
+fn test_binary_roundtrip() {
+    use std::fs;
+    use serde_json;
+    use binary::{BinaryReader, BinaryWriter};
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    {
+        let _thread = TracedThread::new("test_binary_roundtrip", Box::new(events.clone()));
+        for i in 0..100 {
+            let _span = SyncSpan::with_metadata("span", json!({ "i": i }));
+        }
+        let _ = future::err::<(), _>("oh no").traced("failed").wait();
+    }
+    let events = events.lock().unwrap();
+
+    let mut binary = BinaryWriter::new(File::create("/tmp/test_binary_roundtrip.bin").unwrap());
+    let mut json = JsonWriter::new(File::create("/tmp/test_binary_roundtrip.log").unwrap());
+    for event in events.iter() {
+        binary.write(event.clone());
+        json.write(event.clone());
+    }
+    binary.flush();
+    json.flush();
+
+    let reader = BinaryReader::new(File::open("/tmp/test_binary_roundtrip.bin").unwrap()).unwrap();
+    let read = reader.collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(serde_json::to_value(&read).unwrap(), serde_json::to_value(&*events).unwrap());
+
+    let binary_len = fs::metadata("/tmp/test_binary_roundtrip.bin").unwrap().len();
+    let json_len = fs::metadata("/tmp/test_binary_roundtrip.log").unwrap().len();
+    assert!(binary_len * 5 < json_len, "{} bytes binary vs {} bytes json", binary_len, json_len);
+}
+
+#[test]
+fn test_chrome() {
+    use std::io::Read;
+    use serde_json;
+    use chrome::ChromeWriter;
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    {
+        let _thread = TracedThread::new("test_chrome", Box::new(events.clone()));
+        let (tx, rx) = oneshot::channel::<usize>();
+        let events_ = events.clone();
+        let sender = thread::spawn(move || {
+            let _thread = TracedThread::new("test_chrome:sender", Box::new(events_));
+            thread::sleep(Duration::from_millis(10));
+            let _span = SyncSpan::with_metadata("send", json!({ "path": "/foo" }));
+            tx.send(1).unwrap();
+        });
+        rx.traced("rx").wait().unwrap();
+        sender.join().unwrap();
+    }
+
+    {
+        let mut writer = ChromeWriter::new(File::create("/tmp/test_chrome.json").unwrap());
+        for event in events.lock().unwrap().iter() {
+            writer.write(event.clone());
+        }
+    }
+
+    let mut s = String::new();
+    File::open("/tmp/test_chrome.json").unwrap().read_to_string(&mut s).unwrap();
+    let trace: Vec<serde_json::Value> = serde_json::from_str(&s).unwrap();
+    let phases = trace.iter().map(|e| e["ph"].as_str().unwrap()).collect::<Vec<_>>();
+    for ph in &["M", "B", "E", "b", "e", "s", "f"] {
+        assert!(phases.contains(ph), "Missing {:?} in {:?}", ph, phases);
+    }
+    let send = trace.iter().find(|e| e["ph"] == "B" && e["name"] == "send").unwrap();
+    assert_eq!(send["args"]["path"], "/foo");
+}
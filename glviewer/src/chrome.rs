@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::time::Duration;
+
+use cyclotron_backend::{AsyncOutcome, SpanId, TraceEvent};
+use serde_json::Value;
+
+// Does this look like the start of a Chrome trace rather than one of our JSON lines traces? The
+// array form starts with `[`, and the object form is an object whose first key isn't one of our
+// event names.
+pub fn is_chrome_trace(buf: &[u8]) -> bool {
+    let s = String::from_utf8_lossy(buf);
+    let s = s.trim_start();
+    if s.starts_with('[') {
+        return true;
+    }
+    let key = s.strip_prefix('{')
+        .map(|s| s.trim_start())
+        .and_then(|s| s.strip_prefix('"'))
+        .and_then(|s| s.split('"').next());
+    match key {
+        Some(key) => !EVENT_NAMES.contains(&key),
+        None => false,
+    }
+}
+
+const EVENT_NAMES: &[&str] = &[
+    "AsyncStart", "AsyncOnCPU", "AsyncOffCPU", "AsyncEnd",
+    "SyncStart", "SyncEnd",
+    "ThreadStart", "ThreadEnd",
+    "Wakeup",
+];
+
+// A Chrome event with complete events split into begin and end, so we can process everything in
+// timestamp order.
+struct Record {
+    ts: u64,
+    // Ends sort before begins at the same timestamp, and flows after both, so that a flow sees the
+    // slices that are open at its timestamp.  The end of a zero-length slice sorts with the begins
+    // instead, and after its own begin since the sort keeps input order.
+    rank: u8,
+    // Longer complete events begin first so they enclose shorter ones starting at the same time.
+    dur: u64,
+    thread: (u64, u64),
+    ph: char,
+    event: Value,
+}
+
+fn thread_key(event: &Value) -> (u64, u64) {
+    (event["pid"].as_u64().unwrap_or(0), event["tid"].as_u64().unwrap_or(0))
+}
+
+// Async and flow ids can be numbers or strings, and may be scoped by category.
+fn async_key(event: &Value) -> (String, String) {
+    let id = if !event["id2"].is_null() { &event["id2"] } else { &event["id"] };
+    (event["cat"].as_str().unwrap_or("").to_string(), id.to_string())
+}
+
+// Our exporter records how async spans ended in their `e` event's args.
+fn outcome(args: &Value) -> AsyncOutcome {
+    match &args["outcome"] {
+        Value::String(s) if s == "cancelled" => AsyncOutcome::Cancelled,
+        Value::Object(obj) => match obj.get("error") {
+            Some(Value::String(e)) => AsyncOutcome::Error(e.clone()),
+            _ => AsyncOutcome::Success,
+        },
+        _ => AsyncOutcome::Success,
+    }
+}
+
+fn nanos(micros: &Value) -> u64 {
+    (micros.as_f64().unwrap_or(0.0).max(0.0) * 1e3) as u64
+}
+
+struct Converter {
+    events: Vec<TraceEvent>,
+    next_id: u64,
+    thread_names: HashMap<(u64, u64), String>,
+    process_names: HashMap<u64, String>,
+    threads: HashMap<(u64, u64), SpanId>,
+    // Open duration events on each thread.
+    sync_stacks: HashMap<(u64, u64), Vec<SpanId>>,
+    // Open nestable async events with each id.
+    async_stacks: HashMap<(String, String), Vec<SpanId>>,
+    // Async slices marked as on-CPU (as written by our exporter) that are running on each thread.
+    on_cpu: HashMap<(u64, u64), Vec<SpanId>>,
+    // Span that started each flow.
+    flows: HashMap<(String, String), SpanId>,
+    max_ts: u64,
+}
+
+impl Converter {
+    fn new_id(&mut self) -> SpanId {
+        self.next_id += 1;
+        SpanId(self.next_id)
+    }
+
+    fn thread(&mut self, key: (u64, u64), ts: u64) -> SpanId {
+        if let Some(&id) = self.threads.get(&key) {
+            return id;
+        }
+        let id = self.new_id();
+        let name = match (self.process_names.get(&key.0), self.thread_names.get(&key)) {
+            (Some(process), Some(thread)) => format!("{}: {}", process, thread),
+            (None, Some(thread)) => thread.clone(),
+            (Some(process), None) => format!("{}: {}", process, key.1),
+            (None, None) => format!("pid {} tid {}", key.0, key.1),
+        };
+        self.events.push(TraceEvent::ThreadStart { name, id, ts: Duration::from_nanos(ts) });
+        self.threads.insert(key, id);
+        id
+    }
+
+    // The innermost span running on a thread, which is what flows attach to.
+    fn innermost(&mut self, key: (u64, u64), ts: u64) -> SpanId {
+        if let Some(&id) = self.on_cpu.get(&key).and_then(|s| s.last()) {
+            return id;
+        }
+        if let Some(&id) = self.sync_stacks.get(&key).and_then(|s| s.last()) {
+            return id;
+        }
+        self.thread(key, ts)
+    }
+
+    fn process(&mut self, record: Record) {
+        let Record { ts, thread, ph, event, .. } = record;
+        let name = event["name"].as_str().unwrap_or("").to_string();
+        let metadata = event["args"].clone();
+        let t = Duration::from_nanos(ts);
+        self.max_ts = std::cmp::max(self.max_ts, ts);
+
+        match ph {
+            'B' => {
+                let parent_id = self.innermost_sync(thread, ts);
+                let id = self.new_id();
+                self.events.push(TraceEvent::SyncStart { name, id, parent_id, ts: t, metadata });
+                self.sync_stacks.entry(thread).or_default().push(id);
+            }
+            'E' => {
+                if let Some(id) = self.sync_stacks.get_mut(&thread).and_then(|s| s.pop()) {
+                    self.events.push(TraceEvent::SyncEnd { id, ts: t });
+                }
+            }
+            'b' | 'S' => {
+                let key = async_key(&event);
+                let parent = self.async_stacks.get(&key).and_then(|s| s.last()).cloned();
+                match parent {
+                    Some(parent) if metadata["on_cpu"] == Value::Bool(true) => {
+                        let thread_id = Some(self.thread(thread, ts));
+                        self.events.push(TraceEvent::AsyncOnCPU { id: parent, ts: t, thread_id });
+                        self.on_cpu.entry(thread).or_default().push(parent);
+                        // Track the slice so its `e` pops it rather than the task.
+                        self.async_stacks.entry(key).or_default().push(parent);
+                    }
+                    _ => {
+                        let parent_id = match parent {
+                            Some(parent) => parent,
+                            None => self.thread(thread, ts),
+                        };
+                        let id = self.new_id();
+                        self.events.push(TraceEvent::AsyncStart { name, id, parent_id, ts: t, metadata });
+                        self.async_stacks.entry(key).or_default().push(id);
+                    }
+                }
+            }
+            'e' | 'F' => {
+                let key = async_key(&event);
+                let id = match self.async_stacks.get_mut(&key).and_then(|s| s.pop()) {
+                    Some(id) => id,
+                    None => return,
+                };
+                let still_open = self.async_stacks[&key].contains(&id);
+                if still_open {
+                    // This was an on-CPU slice of a task that's still running.
+                    for stack in self.on_cpu.values_mut() {
+                        if let Some(index) = stack.iter().rposition(|&s| s == id) {
+                            stack.remove(index);
+                        }
+                    }
+                    self.events.push(TraceEvent::AsyncOffCPU { id, ts: t });
+                } else {
+                    self.events.push(TraceEvent::AsyncEnd { id, ts: t, outcome: outcome(&metadata) });
+                }
+            }
+            's' => {
+                let waking = self.innermost(thread, ts);
+                self.flows.insert(async_key(&event), waking);
+            }
+            't' | 'f' => {
+                let key = async_key(&event);
+                if let Some(waking_span) = self.flows.get(&key).cloned() {
+                    let parked_span = self.innermost(thread, ts);
+                    if parked_span != waking_span {
+                        self.events.push(TraceEvent::Wakeup { waking_span, parked_span, ts: t });
+                    }
+                    if ph == 't' {
+                        self.flows.insert(key, parked_span);
+                    } else {
+                        self.flows.remove(&key);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn innermost_sync(&mut self, key: (u64, u64), ts: u64) -> SpanId {
+        match self.sync_stacks.get(&key).and_then(|s| s.last()) {
+            Some(&id) => id,
+            None => self.thread(key, ts),
+        }
+    }
+}
+
+/// Convert a Chrome Trace Event Format trace, in either its array or object form, into our events.
+/// Duration and complete events become sync spans, async events become async spans (with nested
+/// slices marked `on_cpu` becoming polls, as our exporter writes them) and flow events become
+/// wakeups.  Everything else is ignored.
+pub fn read_chrome(reader: impl Read) -> Result<Vec<TraceEvent>, String> {
+    let trace: Value = serde_json::from_reader(reader).map_err(|e| e.to_string())?;
+    let raw = match trace {
+        Value::Array(events) => events,
+        Value::Object(mut obj) => match obj.remove("traceEvents") {
+            Some(Value::Array(events)) => events,
+            _ => return Err("Chrome trace is missing traceEvents".into()),
+        },
+        _ => return Err("Chrome trace should be an array or object".into()),
+    };
+
+    let mut converter = Converter {
+        events: Vec::new(),
+        next_id: 0,
+        thread_names: HashMap::new(),
+        process_names: HashMap::new(),
+        threads: HashMap::new(),
+        sync_stacks: HashMap::new(),
+        async_stacks: HashMap::new(),
+        on_cpu: HashMap::new(),
+        flows: HashMap::new(),
+        max_ts: 0,
+    };
+
+    let mut records = Vec::new();
+    // When the open duration events on each thread began, to spot zero-length ones.
+    let mut open: HashMap<(u64, u64), Vec<u64>> = HashMap::new();
+    for event in raw {
+        let ph = event["ph"].as_str().and_then(|p| p.chars().next()).unwrap_or(' ');
+        let thread = thread_key(&event);
+        let ts = nanos(&event["ts"]);
+        match ph {
+            'M' => {
+                let name = event["args"]["name"].as_str().unwrap_or("").to_string();
+                match event["name"].as_str() {
+                    Some("thread_name") => { converter.thread_names.insert(thread, name); }
+                    Some("process_name") => { converter.process_names.insert(thread.0, name); }
+                    _ => (),
+                }
+            }
+            'X' => {
+                let dur = nanos(&event["dur"]);
+                let rank = if dur == 0 { 1 } else { 0 };
+                records.push(Record { ts, rank: 1, dur, thread, ph: 'B', event });
+                records.push(Record { ts: ts + dur, rank, dur: 0, thread, ph: 'E', event: Value::Null });
+            }
+            'B' => {
+                open.entry(thread).or_default().push(ts);
+                records.push(Record { ts, rank: 1, dur: 0, thread, ph, event });
+            }
+            'E' => {
+                let began = open.get_mut(&thread).and_then(|s| s.pop());
+                let rank = if began == Some(ts) { 1 } else { 0 };
+                records.push(Record { ts, rank, dur: 0, thread, ph, event });
+            }
+            'b' | 'S' => records.push(Record { ts, rank: 1, dur: 0, thread, ph, event }),
+            'e' | 'F' => records.push(Record { ts, rank: 0, dur: 0, thread, ph, event }),
+            's' | 't' | 'f' => records.push(Record { ts, rank: 2, dur: 0, thread, ph, event }),
+            _ => (),
+        }
+    }
+    records.sort_by_key(|r| (r.ts, r.rank, std::cmp::Reverse(r.dur)));
+
+    for record in records {
+        converter.process(record);
+    }
+
+    // Close our synthetic threads at the end of the trace.
+    let max_ts = Duration::from_nanos(converter.max_ts);
+    let mut threads: Vec<_> = converter.threads.values().cloned().collect();
+    threads.sort();
+    for id in threads {
+        converter.events.push(TraceEvent::ThreadEnd { id, ts: max_ts });
+    }
+    Ok(converter.events)
+}
@@ -0,0 +1,137 @@
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use crate::db::{Database, Span, TaskId};
+
+/// A piece of a critical path: `task` was running over `span`, or waiting to be woken or polled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    pub task: TaskId,
+    pub span: Span,
+    pub running: bool,
+}
+
+// Add a step to a path we're building backwards, merging it with the one after if they're the
+// same thing.
+fn push(steps: &mut Vec<Step>, step: Step) {
+    if step.span.begin >= step.span.end {
+        return;
+    }
+    if let Some(last) = steps.last_mut() {
+        if last.task == step.task && last.running == step.running && last.span.begin == step.span.end {
+            last.span.begin = step.span.begin;
+            return;
+        }
+    }
+    steps.push(step);
+}
+
+/// What `target` spent its time waiting on, in time order.  We walk backwards from its end: while
+/// a task is being polled it's on the path, and when it's parked we follow the wakeup that got it
+/// polled again to the task that sent it.  A task we reach the start of hands over to its parent,
+/// which started it, and spans without polls count as running the whole time.
+pub fn compute(db: &Database, target: TaskId) -> Vec<Step> {
+    let start = db.task(target).span.begin;
+    let mut steps = vec![];
+    let mut task = target;
+    let mut t = db.task(target).span.end;
+    // Wakeups at the same moment could send us around in circles.
+    let mut visited = HashSet::new();
+    while t > start && visited.insert((task, t)) {
+        let current = db.task(task);
+        if t <= current.span.begin {
+            match current.parent {
+                Some(parent) => task = parent,
+                None => break,
+            }
+            continue;
+        }
+        let polls = match &current.on_cpu {
+            Some(polls) => &polls[..],
+            None => std::slice::from_ref(&current.span),
+        };
+        let poll = polls.iter().rev().find(|poll| poll.begin < t);
+        match poll {
+            Some(poll) if poll.end >= t => {
+                let begin = std::cmp::max(poll.begin, start);
+                push(&mut steps, Step { task, span: Span { begin, end: t }, running: true });
+                t = poll.begin;
+            }
+            _ => {
+                let idle_since = poll.map_or(current.span.begin, |poll| poll.end);
+                let wake = db.parks(task).iter()
+                    .filter(|park| park.nanos > idle_since && park.nanos <= t)
+                    .max_by_key(|park| park.nanos);
+                let begin = wake.map_or(idle_since, |wake| wake.nanos);
+                let span = Span { begin: std::cmp::max(begin, start), end: t };
+                push(&mut steps, Step { task, span, running: false });
+                t = begin;
+                if let Some(wake) = wake {
+                    task = wake.waking;
+                }
+            }
+        }
+    }
+    steps.reverse();
+    steps
+}
+
+// How long a path spent running and waiting in all.
+fn totals(steps: &[Step]) -> String {
+    let time = |running| steps.iter()
+        .filter(|step| step.running == running)
+        .map(|step| step.span.end - step.span.begin)
+        .sum();
+    format!("running {:?}, waiting {:?}", Duration::from_nanos(time(true)), Duration::from_nanos(time(false)))
+}
+
+/// A one line summary of a critical path, for the status bar.
+pub fn describe(db: &Database, target: TaskId, steps: &[Step]) -> String {
+    format!("critical path of {}: {} steps, {}", db.name(db.task(target).full_name), steps.len(), totals(steps))
+}
+
+/// Print a critical path with times relative to the start of `target`, followed by how long it
+/// spent running and waiting in all.
+pub fn write(db: &Database, target: TaskId, steps: &[Step], mut out: impl Write) -> io::Result<()> {
+    let task = db.task(target);
+    writeln!(out, "critical path of {} ({:?}):",
+        db.name(task.full_name), Duration::from_nanos(task.span.end - task.span.begin))?;
+    for step in steps {
+        let duration = step.span.end - step.span.begin;
+        writeln!(out, "  {:>12} {:>12}  {:<7}  {}",
+            format!("+{:?}", Duration::from_nanos(step.span.begin - task.span.begin)),
+            format!("{:?}", Duration::from_nanos(duration)),
+            if step.running { "running" } else { "waiting" },
+            db.name(db.task(step.task).full_name))?;
+    }
+    writeln!(out, "{}", totals(steps))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compute, describe, Step};
+    use crate::db::{Span, TaskId};
+
+    #[test]
+    fn test_critical_path() {
+        let db = crate::db::tests::load_fixture();
+
+        let step = |task, begin: u64, end: u64, running| Step {
+            task: TaskId(task),
+            span: Span { begin: begin * 1_000_000, end: end * 1_000_000 },
+            running,
+        };
+        // `RemoteAdd` was parked from 3ms until `sync` woke it at 5ms, which the thread started at
+        // 4ms, and then it took until 7ms to be polled again.
+        assert_eq!(compute(&db, TaskId(1)), vec![
+            step(0, 1, 4, true),
+            step(2, 4, 5, true),
+            step(1, 5, 7, false),
+            step(1, 7, 8, true),
+        ]);
+        assert_eq!(compute(&db, TaskId(2)), vec![step(2, 4, 6, true)]);
+        let steps = compute(&db, TaskId(1));
+        assert_eq!(describe(&db, TaskId(1), &steps), "critical path of RemoteAdd(/foo): 4 steps, running 5ms, waiting 2ms");
+    }
+}
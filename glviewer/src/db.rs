@@ -2,12 +2,18 @@ use std::io::Read;
 use flate2::read::GzDecoder;
 use regex::Regex;
 use bit_set::BitSet;
+use crate::chrome;
+use crate::diagnostics::{Diagnostics, Problem, ORPHANS};
 use crate::util::Ident;
 use std::collections::{HashMap, HashSet};
 use std::io::{BufReader, BufRead};
 use std::fs::File;
+use cyclotron_backend::{AsyncOutcome, SpanId};
 use cyclotron_backend::TraceEvent as JsonTraceEvent;
+use cyclotron_backend::binary::{self, BinaryReader};
 use std::path::Path;
+use std::time::Duration;
+use serde_json::Value;
 
 #[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]
 pub struct Span {
@@ -43,25 +49,47 @@ impl NameIdSet {
         self.bits.insert(name.0 as usize);
     }
 
-    pub fn overlaps(&self, other: &NameIdSet) -> bool {
-        self.bits.intersection(&other.bits).next().is_some()
-    }
-
-    pub fn count_len(&self) -> usize {
-        self.bits.iter().count()
+    pub fn contains(&self, name: NameId) -> bool {
+        self.bits.contains(name.0 as usize)
     }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, PartialOrd, Hash, Ord)]
 pub struct TaskId(pub u32);
 
+#[derive(Clone, PartialEq)]
+pub struct TaskIdSet {
+    bits: BitSet,
+}
+
+impl TaskIdSet {
+    pub fn new() -> TaskIdSet {
+        TaskIdSet {
+            bits: BitSet::new(),
+        }
+    }
+
+    pub fn insert(&mut self, task: TaskId) {
+        self.bits.insert(task.0 as usize);
+    }
+
+    pub fn contains(&self, task: TaskId) -> bool {
+        self.bits.contains(task.0 as usize)
+    }
+}
+
 #[derive(Debug)]
 pub struct Task {
     pub id: TaskId,
     pub parent: Option<TaskId>,
+    // The simplified name we color and profile by, and the name the span was given.
     pub name: NameId,
+    pub full_name: NameId,
     pub span: Span,
     pub on_cpu: Option<Vec<Span>>,
+    pub metadata: Value,
+    // How an async task ended, if it has.
+    pub outcome: Option<AsyncOutcome>,
 }
 
 #[derive(Copy, Clone)]
@@ -76,6 +104,20 @@ pub struct Park {
     pub nanos: u64,
 }
 
+// How long a task waited to be polled after `waking` woke it, over `span`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Delay {
+    pub waking: TaskId,
+    pub span: Span,
+}
+
+// A task started being polled on `thread` (the first one is recorded too).
+#[derive(Copy, Clone)]
+pub struct Migration {
+    pub thread: TaskId,
+    pub nanos: u64,
+}
+
 struct NameTable {
     by_name: HashMap<String, NameId>,
     names: Vec<String>,
@@ -99,11 +141,88 @@ impl NameTable {
     }
 }
 
+// Events read from a trace, with the line each came from.
+pub type Records = Box<dyn Iterator<Item=(usize, Result<JsonTraceEvent, String>)>>;
+
+// Read the events in a JSON lines, binary or Chrome trace, which may also be gzipped, along with
+// the line each came from (or its index, for formats without lines).  Events that can't be read
+// come back as errors, and we stop after one in a binary trace since we can't find the next record.
+// Only failing to open the trace at all is an error for the whole thing.
+pub fn read_records(path: impl AsRef<Path>) -> Result<Records, String> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let file: Box<dyn Read> = if let Some(ext) = path.extension() {
+        if ext == "gz" {
+            eprintln!("decoding gzip...");
+            Box::new(GzDecoder::new(file))
+        } else {
+            Box::new(file)
+        }
+    } else {
+        Box::new(file)
+    };
+    let mut file = BufReader::new(file);
+
+    let head = file.fill_buf().map_err(|e| format!("{}: {}", path.display(), e))?;
+    let records: Records = if head.starts_with(binary::MAGIC) {
+        let mut reader = match BinaryReader::new(file) {
+            Ok(reader) => reader,
+            Err(e) => return Ok(Box::new(std::iter::once((0, Err(e.to_string()))))),
+        };
+        let mut index = 0;
+        let mut failed = false;
+        Box::new(std::iter::from_fn(move || {
+            if failed {
+                return None;
+            }
+            index += 1;
+            let event = reader.next()?.map_err(|e| e.to_string());
+            failed = event.is_err();
+            Some((index, event))
+        }))
+    } else if chrome::is_chrome_trace(head) {
+        match chrome::read_chrome(file) {
+            Ok(events) => Box::new(events.into_iter().enumerate().map(|(i, event)| (i + 1, Ok(event)))),
+            Err(e) => Box::new(std::iter::once((0, Err(e)))),
+        }
+    } else {
+        let mut line = 0;
+        let mut failed = false;
+        Box::new(std::iter::from_fn(move || {
+            if failed {
+                return None;
+            }
+            // Lines are read as bytes so one that isn't UTF-8 is just a malformed event.
+            let mut buf = Vec::new();
+            line += 1;
+            let num_read = match file.read_until(b'\n', &mut buf) {
+                Ok(num_read) => num_read,
+                Err(e) => {
+                    failed = true;
+                    return Some((line, Err(e.to_string())));
+                }
+            };
+
+            // Stop at a partially written last line.
+            if num_read == 0 || !buf.ends_with(b"\n") {
+                None
+            } else {
+                buf.pop();
+                Some((line, serde_json::from_slice(&buf).map_err(|e| e.to_string())))
+            }
+        }))
+    };
+    Ok(records)
+}
+
 pub struct Database {
     names: NameTable,
     pub tasks: Vec<Task>,
     wakes: Vec<Vec<Wake>>,
     parks: Vec<Vec<Park>>,
+    migrations: Vec<Vec<Migration>>,
+    roots: Vec<TaskId>,
+    thread_tasks: HashMap<TaskId, Vec<TaskId>>,
 }
 
 impl Database {
@@ -137,167 +256,730 @@ impl Database {
         &self.parks[task.0 as usize]
     }
 
+    /// The scheduling delay after each wakeup of an async task, from the wakeup to the start of
+    /// its next poll.  Wakeups that weren't followed by a poll are left out.
+    pub fn scheduling_delays(&self, task: TaskId) -> impl Iterator<Item=Delay> + '_ {
+        let polls = self.task(task).on_cpu.as_deref().unwrap_or(&[]);
+        self.parks(task).iter().filter_map(move |park| {
+            let next = polls.partition_point(|poll| poll.begin < park.nanos);
+            polls.get(next).map(|poll| Delay {
+                waking: park.waking,
+                span: Span { begin: park.nanos, end: poll.begin },
+            })
+        })
+    }
+
+    pub fn migrations(&self, task: TaskId) -> &[Migration] {
+        &self.migrations[task.0 as usize]
+    }
+
     pub fn task(&self, task: TaskId) -> &Task {
         &self.tasks[task.0 as usize]
     }
 
     #[cfg(test)]
     pub fn test(tasks: Vec<Task>) -> Self {
+        let mut db = Self::new();
+        for task in tasks {
+            db.add_task(task);
+        }
+        db
+    }
+
+    pub fn new() -> Self {
         Self {
-            tasks,
             names: NameTable::new(),
+            tasks: vec![],
             wakes: vec![],
             parks: vec![],
+            migrations: vec![],
+            roots: vec![],
+            thread_tasks: HashMap::new(),
+        }
+    }
+
+    fn add_task(&mut self, task: Task) {
+        let root = match task.parent {
+            Some(parent) => self.roots[parent.0 as usize],
+            None => task.id,
+        };
+        self.roots.push(root);
+        self.thread_tasks.entry(root).or_default().push(task.id);
+        self.tasks.push(task);
+        self.wakes.push(Vec::new());
+        self.parks.push(Vec::new());
+        self.migrations.push(Vec::new());
+    }
+
+    /// The root task of the thread `task` is in.
+    pub fn root(&self, task: TaskId) -> TaskId {
+        self.roots[task.0 as usize]
+    }
+
+    /// Every task in the thread rooted at `root`, including itself, in the order they started.
+    pub fn thread_tasks(&self, root: TaskId) -> &[TaskId] {
+        &self.thread_tasks[&root]
+    }
+
+    #[cfg(test)]
+    pub fn load(path: impl AsRef<Path>) -> (Database, Diagnostics) {
+        Database::load_with_rules(path, NameRules::default()).unwrap()
+    }
+
+    /// Load a trace, working around any problems in it rather than panicking, and report what they
+    /// were.  Spans whose parent is missing are attached to a synthetic `<orphans>` root, and other
+    /// events that don't make sense are dropped.  Names are simplified with `rules`.  It's only an
+    /// error if the trace can't be opened.
+    pub fn load_with_rules(path: impl AsRef<Path>, rules: NameRules) -> Result<(Database, Diagnostics), String> {
+        let mut db = Database::new();
+        let mut loader = Loader::with_rules(rules);
+        for (line, event) in read_records(path)? {
+            loader.push(&mut db, line, event);
         }
+        loader.finish_batch(&mut db);
+        Ok((db, loader.diagnostics))
+    }
+}
+
+/// Regex rewrite rules that turn a span's name into the simplified name we group spans by for
+/// coloring and profile mode, applied in order.  By default names are cut off at the first `(` or
+/// `{`, so `RemoteAdd(/foo)` and `RemoteAdd(/bar)` are both `RemoteAdd`.
+#[derive(Debug)]
+pub struct NameRules {
+    rules: Vec<(Regex, String)>,
+}
+
+impl Default for NameRules {
+    fn default() -> Self {
+        NameRules { rules: vec![(Regex::new(r"(?s)[({].*").unwrap(), String::new())] }
+    }
+}
+
+impl NameRules {
+    /// No rules, so spans are grouped by their full names.
+    pub fn none() -> Self {
+        NameRules { rules: vec![] }
     }
 
-    pub fn load(path: impl AsRef<Path>) -> Database {
-        let mut unclosed = HashSet::new();
-        let mut tasks = Vec::new();
-        let mut unterminated = HashMap::new();
-        let mut task_ids = HashMap::new();
-        let mut names = NameTable::new();
-        let mut wakes_wip = Vec::new();
+    /// Add a rule written as `PATTERN => REPLACEMENT`, where the replacement can refer to the
+    /// pattern's groups as `$1` or `$name`.
+    pub fn push(&mut self, rule: &str) -> Result<(), String> {
+        let (pattern, replacement) = rule.split_once("=>")
+            .ok_or_else(|| format!("expected PATTERN => REPLACEMENT in {:?}", rule))?;
+        let regex = Regex::new(pattern.trim()).map_err(|e| format!("bad pattern in {:?}: {}", rule, e))?;
+        self.rules.push((regex, replacement.trim().to_string()));
+        Ok(())
+    }
+
+    /// Read rules from a file with one per line, skipping blank lines and `#` comments.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, String> {
         let path = path.as_ref();
-        let file = File::open(path).unwrap();
-        let file: Box<dyn Read> = if let Some(ext) = path.extension() {
-            if ext == "gz" {
-                println!("decoding gzip...");
-                Box::new(GzDecoder::new(file))
-            } else {
-                Box::new(file)
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut rules = NameRules::none();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
             }
-        } else {
-            Box::new(file)
-        };
-        let mut file = BufReader::new(file);
+            rules.push(line).map_err(|e| format!("{}:{}: {}", path.display(), i + 1, e))?;
+        }
+        Ok(rules)
+    }
 
-        let mut max_ts = 0;
+    pub fn simplify(&self, name: &str) -> String {
+        let mut name = name.to_string();
+        for (regex, replacement) in &self.rules {
+            name = regex.replace_all(&name, replacement.as_str()).into_owned();
+        }
+        name
+    }
+}
 
-        fn simplify_name(mut name: String) -> String {
-            let paren = name.find('(');
-            let curly = name.find('{');
-            let limit = match (paren, curly) {
-                (Some(a), Some(b)) => std::cmp::min(a, b),
-                (_, Some(v)) | (Some(v), _) => v,
-                _ => name.len(),
-            };
-            name.truncate(limit);
-            name
+/// Builds up a `Database` one event at a time, so we can keep extending it while a trace is still
+/// being written.  Spans that haven't ended (and polls that haven't finished) are drawn up to the
+/// latest timestamp we've seen, which `finish_batch` brings up to date.
+pub struct Loader {
+    pub diagnostics: Diagnostics,
+    task_ids: HashMap<SpanId, TaskId>,
+    unclosed: HashSet<TaskId>,
+    // Async tasks that are on CPU, whose last `on_cpu` span is still open.
+    unterminated: HashSet<TaskId>,
+    last_thread: HashMap<TaskId, TaskId>,
+    orphans: Option<TaskId>,
+    max_ts: u64,
+    // How far we've extended open spans.
+    extended_ts: u64,
+    // Roots of the threads that have changed since the last `finish_batch`.
+    dirty: HashSet<TaskId>,
+    rules: NameRules,
+}
+
+impl Loader {
+    #[cfg(test)]
+    pub fn new() -> Self {
+        Loader::with_rules(NameRules::default())
+    }
+
+    pub fn with_rules(rules: NameRules) -> Self {
+        Loader {
+            diagnostics: Diagnostics::default(),
+            task_ids: HashMap::new(),
+            unclosed: HashSet::new(),
+            unterminated: HashSet::new(),
+            last_thread: HashMap::new(),
+            orphans: None,
+            max_ts: 0,
+            extended_ts: 0,
+            dirty: HashSet::new(),
+            rules,
         }
+    }
 
-        loop {
-            let mut buf = String::new();
-            let num_read = file.read_line(&mut buf).unwrap();
+    fn task_id(&mut self, line: usize, id: SpanId) -> Option<TaskId> {
+        let tid = self.task_ids.get(&id).cloned();
+        if tid.is_none() {
+            self.diagnostics.push(line, Problem::UnknownSpan(id));
+        }
+        tid
+    }
 
-            if num_read == 0 || !buf.ends_with("\n") {
-                break;
-            } else {
-                buf.pop();
-                match serde_json::from_str(&buf).unwrap() {
-                    JsonTraceEvent::AsyncStart { id, ts, name, parent_id, metadata: _ } => {
-                        let tid = TaskId(task_ids.len() as u32);
-                        assert!(task_ids.insert(id, tid).is_none());
-                        let parent = task_ids[&parent_id];
-                        max_ts = std::cmp::max(ts.as_nanos() as u64, max_ts);
-                        assert!(unclosed.insert(tid));
-                        tasks.push(Task {
-                            id: tid,
-                            parent: Some(parent),
-                            name: names.insert(simplify_name(name)),
-                            span: Span { begin: ts.as_nanos() as u64, end: std::u64::MAX },
-                            on_cpu: Some(Vec::new()),
-                        });
-                    }
-                    JsonTraceEvent::AsyncOnCPU { id, ts } => {
-                        let tid = task_ids[&id];
-                        max_ts = std::cmp::max(ts.as_nanos() as u64, max_ts);
-                        assert!(unterminated.insert(tid, ts.as_nanos() as u64).is_none());
-                    }
-                    JsonTraceEvent::AsyncOffCPU { id, ts,  } => {
-                        let tid = task_ids[&id];
-                        let begin = unterminated.remove(&tid).unwrap();
-                        let end = ts.as_nanos() as u64;
-                        max_ts = std::cmp::max(ts.as_nanos() as u64, max_ts);
-                        tasks[tid.0 as usize].on_cpu.as_mut().unwrap().push(Span { begin, end });
-                    }
-                    JsonTraceEvent::AsyncEnd { id, ts, outcome: _ } => {
-                        let tid = task_ids[&id];
-                        assert!(unclosed.remove(&tid));
-                        max_ts = std::cmp::max(ts.as_nanos() as u64, max_ts);
-                        tasks[tid.0 as usize].span.end = ts.as_nanos() as u64;
-                    }
-                    JsonTraceEvent::SyncStart { id, ts, name, parent_id, metadata: _ } => {
-                        let tid = TaskId(task_ids.len() as u32);
-                        assert!(task_ids.insert(id, tid).is_none());
-                        let parent = task_ids[&parent_id];
-                        max_ts = std::cmp::max(ts.as_nanos() as u64, max_ts);
-                        assert!(unclosed.insert(tid));
-                        tasks.push(Task {
-                            id: tid,
-                            parent: Some(parent),
-                            name: names.insert(simplify_name(name)),
-                            span: Span { begin: ts.as_nanos() as u64, end: std::u64::MAX },
-                            on_cpu: None,
-                        });
-                    }
-                    JsonTraceEvent::SyncEnd { id, ts } => {
-                        let tid = task_ids[&id];
-                        assert!(unclosed.remove(&tid));
-                        max_ts = std::cmp::max(ts.as_nanos() as u64, max_ts);
-                        tasks[tid.0 as usize].span.end = ts.as_nanos() as u64;
-                    }
-                    JsonTraceEvent::ThreadStart { id, ts, name } => {
-                        let tid = TaskId(task_ids.len() as u32);
-                        assert!(task_ids.insert(id, tid).is_none());
-                        max_ts = std::cmp::max(ts.as_nanos() as u64, max_ts);
-                        assert!(unclosed.insert(tid));
-                        tasks.push(Task {
-                            id: tid,
-                            parent: None,
-                            name: names.insert(simplify_name(name)),
-                            span: Span { begin: ts.as_nanos() as u64, end: std::u64::MAX },
-                            on_cpu: None,
-                        });
-                    }
-                    JsonTraceEvent::ThreadEnd { id, ts } => {
-                        let tid = task_ids[&id];
-                        assert!(unclosed.remove(&tid));
-                        max_ts = std::cmp::max(ts.as_nanos() as u64, max_ts);
-                        tasks[tid.0 as usize].span.end = ts.as_nanos() as u64;
+    // End a span, returning its task if it was open.
+    fn end(&mut self, db: &mut Database, line: usize, id: SpanId, ts: Duration) -> Option<TaskId> {
+        let tid = self.task_id(line, id)?;
+        if !self.unclosed.remove(&tid) {
+            self.diagnostics.push(line, Problem::AlreadyEnded(id));
+            return None;
+        }
+        db.tasks[tid.0 as usize].span.end = ts.as_nanos() as u64;
+        self.dirty.insert(db.root(tid));
+        Some(tid)
+    }
+
+    pub fn push(&mut self, db: &mut Database, line: usize, event: Result<JsonTraceEvent, String>) {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                self.diagnostics.push(line, Problem::Malformed(e));
+                return;
+            }
+        };
+        self.max_ts = std::cmp::max(event.ts().as_nanos() as u64, self.max_ts);
+
+        // Everything but the start of a span is handled in the match, leaving the starts, which all
+        // work the same way, to below.
+        let (id, ts, name, parent_id, on_cpu, metadata) = match event {
+            JsonTraceEvent::AsyncStart { id, ts, name, parent_id, metadata } => {
+                (id, ts, name, Some(parent_id), Some(Vec::new()), metadata)
+            }
+            JsonTraceEvent::SyncStart { id, ts, name, parent_id, metadata } => {
+                (id, ts, name, Some(parent_id), None, metadata)
+            }
+            JsonTraceEvent::ThreadStart { id, ts, name } => (id, ts, name, None, None, Value::Null),
+            JsonTraceEvent::AsyncOnCPU { id, ts, thread_id } => {
+                let tid = match self.task_id(line, id) {
+                    Some(tid) => tid,
+                    None => return,
+                };
+                let task = &mut db.tasks[tid.0 as usize];
+                let on_cpu = match task.on_cpu.as_mut() {
+                    Some(on_cpu) => on_cpu,
+                    None => {
+                        self.diagnostics.push(line, Problem::NotAsync(id));
+                        return;
                     }
-                    JsonTraceEvent::Wakeup { waking_span, parked_span, ts } => {
-                        max_ts = std::cmp::max(ts.as_nanos() as u64, max_ts);
-                        wakes_wip.push((waking_span, parked_span, ts.as_nanos() as u64));
+                };
+                if !self.unterminated.insert(tid) {
+                    self.diagnostics.push(line, Problem::AlreadyOnCpu(id));
+                    return;
+                }
+                let begin = ts.as_nanos() as u64;
+                on_cpu.push(Span { begin, end: begin });
+                self.dirty.insert(db.root(tid));
+                if let Some(thread_id) = thread_id {
+                    match self.task_ids.get(&thread_id) {
+                        Some(&thread) => {
+                            if self.last_thread.insert(tid, thread) != Some(thread) {
+                                let migration = Migration { thread, nanos: begin };
+                                db.migrations[tid.0 as usize].push(migration);
+                            }
+                        }
+                        None => self.diagnostics.push(line, Problem::UnknownThread(thread_id)),
                     }
                 }
+                return;
+            }
+            JsonTraceEvent::AsyncOffCPU { id, ts } => {
+                let tid = match self.task_id(line, id) {
+                    Some(tid) => tid,
+                    None => return,
+                };
+                if !self.unterminated.remove(&tid) {
+                    self.diagnostics.push(line, Problem::NotOnCpu(id));
+                    return;
+                }
+                let on_cpu = db.tasks[tid.0 as usize].on_cpu.as_mut().unwrap();
+                on_cpu.last_mut().unwrap().end = ts.as_nanos() as u64;
+                self.dirty.insert(db.root(tid));
+                return;
+            }
+            JsonTraceEvent::AsyncEnd { id, ts, outcome } => {
+                if let Some(tid) = self.end(db, line, id, ts) {
+                    db.tasks[tid.0 as usize].outcome = Some(outcome);
+                }
+                return;
+            }
+            JsonTraceEvent::SyncEnd { id, ts } |
+            JsonTraceEvent::ThreadEnd { id, ts } => {
+                self.end(db, line, id, ts);
+                return;
+            }
+            JsonTraceEvent::Wakeup { waking_span, parked_span, ts } => {
+                let waking = self.task_id(line, waking_span);
+                let parked = self.task_id(line, parked_span);
+                if let (Some(waking), Some(parked)) = (waking, parked) {
+                    let nanos = ts.as_nanos() as u64;
+                    db.wakes[waking.0 as usize].push(Wake { parked, nanos });
+                    db.parks[parked.0 as usize].push(Park { waking, nanos });
+                }
+                return;
+            }
+        };
+
+        if self.task_ids.contains_key(&id) {
+            self.diagnostics.push(line, Problem::DuplicateId(id));
+            return;
+        }
+        let begin = ts.as_nanos() as u64;
+        let parent = match parent_id {
+            Some(parent_id) => match self.task_ids.get(&parent_id) {
+                Some(&parent) => Some(parent),
+                None => {
+                    self.diagnostics.push(line, Problem::MissingParent { id, parent: parent_id });
+                    let root = match self.orphans {
+                        Some(root) => root,
+                        None => {
+                            let root = TaskId(db.tasks.len() as u32);
+                            let name = db.names.insert(ORPHANS.to_string());
+                            db.add_task(Task {
+                                id: root,
+                                parent: None,
+                                name,
+                                full_name: name,
+                                span: Span { begin, end: begin },
+                                on_cpu: None,
+                                metadata: Value::Null,
+                                outcome: None,
+                            });
+                            self.unclosed.insert(root);
+                            self.orphans = Some(root);
+                            root
+                        }
+                    };
+                    // The orphans' root covers every orphan.
+                    let span = &mut db.tasks[root.0 as usize].span;
+                    span.begin = std::cmp::min(span.begin, begin);
+                    Some(root)
+                }
+            },
+            None => None,
+        };
+        let tid = TaskId(db.tasks.len() as u32);
+        self.task_ids.insert(id, tid);
+        self.unclosed.insert(tid);
+        let simplified = db.names.insert(self.rules.simplify(&name));
+        let full_name = db.names.insert(name);
+        db.add_task(Task {
+            id: tid,
+            parent,
+            name: simplified,
+            full_name,
+            span: Span { begin, end: begin },
+            on_cpu,
+            metadata,
+            outcome: None,
+        });
+        self.dirty.insert(db.root(tid));
+    }
+
+    /// Extend everything that's still open to the latest timestamp, and return the roots of the
+    /// threads that changed since the last call.
+    pub fn finish_batch(&mut self, db: &mut Database) -> Vec<TaskId> {
+        // Open spans only change threads that haven't otherwise changed if time has moved on.
+        let grown = self.max_ts != self.extended_ts;
+        self.extended_ts = self.max_ts;
+        for &tid in &self.unterminated {
+            let on_cpu = db.tasks[tid.0 as usize].on_cpu.as_mut().unwrap();
+            on_cpu.last_mut().unwrap().end = self.max_ts;
+            if grown {
+                self.dirty.insert(db.root(tid));
             }
         }
+        for &tid in &self.unclosed {
+            db.tasks[tid.0 as usize].span.end = self.max_ts;
+            if grown {
+                self.dirty.insert(db.root(tid));
+            }
+        }
+        let mut dirty: Vec<_> = self.dirty.drain().collect();
+        dirty.sort();
+        dirty
+    }
+}
+
+/// Tails a JSON lines trace that's still being written, handing each complete line to a `Loader`
+/// as it appears.
+pub struct Follower {
+    file: BufReader<File>,
+    // Bytes rather than a string, since the writer may be partway through a character.
+    partial: Vec<u8>,
+    line: usize,
+}
 
-        for (tid, begin) in unterminated {
-            let end = max_ts;
-            tasks[tid.0 as usize].on_cpu.as_mut().unwrap().push(Span { begin, end });
+impl Follower {
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, String> {
+        let mut file = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+        let head = file.fill_buf().map_err(|e| e.to_string())?;
+        if head.starts_with(binary::MAGIC) || chrome::is_chrome_trace(head) || head.starts_with(&[0x1f, 0x8b]) {
+            return Err("only uncompressed JSON lines traces can be followed".into());
         }
+        Ok(Follower {
+            file,
+            partial: Vec::new(),
+            line: 0,
+        })
+    }
 
-        for tid in unclosed {
-            tasks[tid.0 as usize].span.end = max_ts;
+    /// Load every complete line that's been written since the last call, keeping any partial last
+    /// line for next time, and return the roots of the threads that changed.  If the trace can't be
+    /// read, the lines loaded so far are kept and picked up by the next successful call.
+    pub fn poll(&mut self, loader: &mut Loader, db: &mut Database) -> Result<Vec<TaskId>, String> {
+        loop {
+            let num_read = self.file.read_until(b'\n', &mut self.partial).map_err(|e| e.to_string())?;
+            if num_read == 0 || !self.partial.ends_with(b"\n") {
+                break;
+            }
+            self.partial.pop();
+            self.line += 1;
+            let event = serde_json::from_slice(&self.partial).map_err(|e| e.to_string());
+            loader.push(db, self.line, event);
+            self.partial.clear();
         }
+        Ok(loader.finish_batch(db))
+    }
+}
 
-        let mut wakes: Vec<Vec<Wake>> = std::iter::repeat(Vec::new()).take(tasks.len()).collect();
-        let mut parks: Vec<Vec<Park>> = std::iter::repeat(Vec::new()).take(tasks.len()).collect();
+#[cfg(test)]
+pub mod tests {
+    use super::{Database, Delay, NameRules, Span};
+    use crate::diagnostics::{Problem, ORPHANS};
+    use cyclotron_backend::{Logger, SpanId, TraceEvent};
+    use cyclotron_backend::binary::BinaryWriter;
+    use cyclotron_backend::json::JsonWriter;
+    use std::fs::File;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    pub fn events() -> Vec<TraceEvent> {
+        let ts = Duration::from_millis;
+        vec![
+            TraceEvent::ThreadStart { name: "thread".into(), id: SpanId(1), ts: ts(0) },
+            TraceEvent::AsyncStart {
+                name: "RemoteAdd(/foo)".into(),
+                id: SpanId(2),
+                parent_id: SpanId(1),
+                ts: ts(1),
+                metadata: serde_json::json!({ "path": "/foo", "request": { "attempt": 2 } }),
+            },
+            TraceEvent::AsyncOnCPU { id: SpanId(2), ts: ts(2), thread_id: Some(SpanId(1)) },
+            TraceEvent::AsyncOffCPU { id: SpanId(2), ts: ts(3) },
+            TraceEvent::SyncStart {
+                name: "sync".into(),
+                id: SpanId(3),
+                parent_id: SpanId(1),
+                ts: ts(4),
+                metadata: serde_json::Value::Null,
+            },
+            TraceEvent::Wakeup { waking_span: SpanId(3), parked_span: SpanId(2), ts: ts(5) },
+            TraceEvent::SyncEnd { id: SpanId(3), ts: ts(6) },
+            TraceEvent::AsyncOnCPU { id: SpanId(2), ts: ts(7), thread_id: Some(SpanId(1)) },
+            TraceEvent::AsyncOffCPU { id: SpanId(2), ts: ts(8) },
+            TraceEvent::AsyncEnd {
+                id: SpanId(2),
+                ts: ts(8),
+                outcome: cyclotron_backend::AsyncOutcome::Success,
+            },
+            TraceEvent::ThreadEnd { id: SpanId(1), ts: ts(9) },
+        ]
+    }
 
-        for (waking_span, parked_span, nanos) in wakes_wip {
-            let waking_span = task_ids[&waking_span];
-            let parked_span = task_ids[&parked_span];
-            wakes[waking_span.0 as usize].push(Wake { parked: parked_span, nanos });
-            parks[parked_span.0 as usize].push(Park { waking: waking_span, nanos });
+    pub fn write(mut logger: impl Logger) {
+        for event in events() {
+            logger.write(event);
         }
+        logger.flush();
+    }
+
+    // A path in the temp directory that no other test, in this run or another, is using.
+    pub fn temp_path(name: &str) -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let unique = format!("glviewer_test_{}_{}_{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed), name);
+        std::env::temp_dir().join(unique)
+    }
+
+    // The trace from `events`, loaded through a JSON lines file.
+    pub fn load_fixture() -> Database {
+        let path = temp_path("fixture.log");
+        write(JsonWriter::new(File::create(&path).unwrap()));
+        let (db, diagnostics) = Database::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(diagnostics.is_empty());
+        db
+    }
+
+    #[test]
+    fn test_load_binary() {
+        let json_path = "/tmp/glviewer_test_load.log";
+        let binary_path = "/tmp/glviewer_test_load.bin";
+        write(JsonWriter::new(File::create(json_path).unwrap()));
+        write(BinaryWriter::new(File::create(binary_path).unwrap()));
+
+        let (json, _) = Database::load(json_path);
+        let (binary, diagnostics) = Database::load(binary_path);
+        assert!(diagnostics.is_empty());
+        assert_eq!(format!("{:?}", json.tasks), format!("{:?}", binary.tasks));
+        assert_eq!(binary.tasks.len(), 3);
+        assert_eq!(binary.parks(binary.tasks[1].id).len(), 1);
+    }
+
+    #[test]
+    fn test_scheduling_delays() {
+        let db = load_fixture();
+
+        // `sync` wakes `RemoteAdd` at 5ms, and it's next polled at 7ms.
+        let delays: Vec<_> = db.scheduling_delays(db.tasks[1].id).collect();
+        assert_eq!(delays, vec![Delay { waking: db.tasks[2].id, span: Span { begin: 5_000_000, end: 7_000_000 } }]);
+        assert_eq!(db.scheduling_delays(db.tasks[2].id).count(), 0);
+    }
+
+    #[test]
+    fn test_load_chrome() {
+        let path = "/tmp/glviewer_test_load_chrome.json";
+        std::fs::write(path, r#"{
+            "displayTimeUnit": "ms",
+            "traceEvents": [
+                {"ph": "M", "name": "thread_name", "pid": 1, "tid": 7, "args": {"name": "main"}},
+                {"ph": "X", "name": "outer", "ts": 0, "dur": 10, "pid": 1, "tid": 7},
+                {"ph": "X", "name": "inner", "ts": 0, "dur": 4, "pid": 1, "tid": 7},
+                {"ph": "b", "cat": "rpc", "name": "call", "id": 1, "ts": 2, "pid": 1, "tid": 7},
+                {"ph": "s", "cat": "flow", "name": "wake", "id": 5, "ts": 3, "pid": 1, "tid": 7},
+                {"ph": "B", "name": "handler", "ts": 5, "pid": 1, "tid": 8},
+                {"ph": "f", "bp": "e", "cat": "flow", "name": "wake", "id": 5, "ts": 6, "pid": 1, "tid": 8},
+                {"ph": "E", "ts": 8, "pid": 1, "tid": 8},
+                {"ph": "e", "cat": "rpc", "name": "call", "id": 1, "ts": 9, "pid": 1, "tid": 7}
+            ]
+        }"#).unwrap();
+
+        let (db, diagnostics) = Database::load(path);
+        assert!(diagnostics.is_empty());
+        let names: Vec<_> = db.tasks.iter().map(|t| db.name(t.name)).collect();
+        assert_eq!(names, vec!["main", "outer", "inner", "call", "pid 1 tid 8", "handler"]);
+        assert_eq!(db.tasks[2].parent, Some(db.tasks[1].id));
+        assert_eq!(db.tasks[3].parent, Some(db.tasks[0].id));
+        assert!(db.tasks[3].on_cpu.is_some());
+        assert_eq!(db.tasks[2].span.end, 4_000);
+
+        // The flow starts inside `inner` and ends inside `handler`.
+        assert_eq!(db.parks(db.tasks[5].id).len(), 1);
+        assert_eq!(db.wakes(db.tasks[2].id).len(), 1);
+    }
+
+    #[test]
+    fn test_load_chrome_zero_length() {
+        let path = "/tmp/glviewer_test_load_chrome_zero_length.json";
+        std::fs::write(path, r#"[
+            {"ph": "X", "name": "outer", "ts": 0, "dur": 10, "pid": 1, "tid": 7},
+            {"ph": "X", "name": "marker", "ts": 5, "dur": 0, "pid": 1, "tid": 7},
+            {"ph": "B", "name": "instant", "ts": 6, "pid": 1, "tid": 7},
+            {"ph": "E", "ts": 6, "pid": 1, "tid": 7}
+        ]"#).unwrap();
+
+        // Both zero-length slices end where they begin, inside `outer`.
+        let (db, diagnostics) = Database::load(path);
+        assert!(diagnostics.is_empty());
+        let spans: Vec<_> = db.tasks.iter().map(|t| (db.name(t.name), t.parent, t.span)).collect();
+        assert_eq!(spans[1..], [
+            ("outer", Some(db.tasks[0].id), Span { begin: 0, end: 10_000 }),
+            ("marker", Some(db.tasks[1].id), Span { begin: 5_000, end: 5_000 }),
+            ("instant", Some(db.tasks[1].id), Span { begin: 6_000, end: 6_000 }),
+        ]);
+    }
 
-        Database {
-            names,
-            tasks,
-            wakes,
-            parks,
+    #[test]
+    fn test_name_rules() {
+        let path = "/tmp/glviewer_test_name_rules.log";
+        write(JsonWriter::new(File::create(path).unwrap()));
+
+        let (db, _) = Database::load(path);
+        assert_eq!(db.name(db.tasks[1].name), "RemoteAdd");
+        assert_eq!(db.name(db.tasks[1].full_name), "RemoteAdd(/foo)");
+
+        let mut rules = NameRules::none();
+        rules.push(r"^(\w+)\((/\w+).* => $1 $2").unwrap();
+        let (db, _) = Database::load_with_rules(path, rules).unwrap();
+        assert_eq!(db.name(db.tasks[1].name), "RemoteAdd /foo");
+        assert_eq!(db.name(db.tasks[2].name), "sync");
+
+        let (db, _) = Database::load_with_rules(path, NameRules::none()).unwrap();
+        assert_eq!(db.tasks[1].name, db.tasks[1].full_name);
+
+        let rules_path = "/tmp/glviewer_test_name_rules.txt";
+        std::fs::write(rules_path, "# Drop arguments.\n\n\\(.* => \nRemote =>\n").unwrap();
+        let rules = NameRules::read(rules_path).unwrap();
+        assert_eq!(rules.simplify("RemoteAdd(/foo)"), "Add");
+        std::fs::write(rules_path, "Remote\n").unwrap();
+        assert!(NameRules::read(rules_path).unwrap_err().ends_with(":1: expected PATTERN => REPLACEMENT in \"Remote\""));
+        assert!(NameRules::none().push("( => x").is_err());
+    }
+
+    #[test]
+    fn test_load_chrome_export() {
+        let json_path = "/tmp/glviewer_test_chrome_export.log";
+        let chrome_path = "/tmp/glviewer_test_chrome_export.json";
+        write(JsonWriter::new(File::create(json_path).unwrap()));
+        assert!(crate::export::chrome(json_path, chrome_path).unwrap().is_empty());
+
+        // Threads come back with an extra sync span for their `B`/`E` events.
+        let (db, diagnostics) = Database::load(chrome_path);
+        assert!(diagnostics.is_empty());
+        let names: Vec<_> = db.tasks.iter().map(|t| db.name(t.name)).collect();
+        assert_eq!(names, vec!["thread", "thread", "RemoteAdd", "sync"]);
+        let task = &db.tasks[2];
+        assert_eq!(task.on_cpu.as_ref().unwrap().len(), 2);
+        assert_eq!(db.parks(task.id).len(), 1);
+        assert_eq!(db.parks(task.id)[0].waking, db.tasks[3].id);
+
+        // A bad line is reported and skipped, and a missing trace is an error rather than a panic.
+        let mut contents = std::fs::read(json_path).unwrap();
+        contents.extend_from_slice(b"not json\n");
+        std::fs::write(json_path, contents).unwrap();
+        let diagnostics = crate::export::chrome(json_path, chrome_path).unwrap();
+        assert_eq!(diagnostics.problems.len(), 1);
+        assert_eq!(Database::load(chrome_path).0.tasks.len(), 4);
+        assert!(crate::export::chrome("/tmp/glviewer_test_chrome_export_missing.log", chrome_path).is_err());
+    }
+
+    #[test]
+    fn test_load_malformed() {
+        let path = "/tmp/glviewer_test_load_malformed.log";
+        std::fs::write(path, concat!(
+            r#"{"ThreadStart":{"name":"thread","id":1,"ts":{"secs":0,"nanos":0}}}"#, "\n",
+            r#"{"AsyncStart":{"name":"a","id":2,"parent_id":1,"ts":{"secs":1,"nanos":0},"metadata":null}}"#, "\n",
+            r#"{"AsyncStart":{"name":"b","id":2,"parent_id":1,"ts":{"secs":1,"nanos":0},"metadata":null}}"#, "\n",
+            "not json\n",
+            r#"{"AsyncStart":{"name":"c","id":3,"parent_id":9,"ts":{"secs":2,"nanos":0},"metadata":null}}"#, "\n",
+            r#"{"AsyncOffCPU":{"id":2,"ts":{"secs":3,"nanos":0}}}"#, "\n",
+            r#"{"AsyncOnCPU":{"id":4,"ts":{"secs":3,"nanos":0}}}"#, "\n",
+            r#"{"AsyncEnd":{"id":2,"ts":{"secs":4,"nanos":0},"outcome":"Success"}}"#, "\n",
+            r#"{"AsyncEnd":{"id":2,"ts":{"secs":4,"nanos":0},"outcome":"Success"}}"#, "\n",
+        )).unwrap();
+
+        let (db, diagnostics) = Database::load(path);
+        let problems: Vec<_> = diagnostics.problems.iter()
+            .map(|(line, problem)| (*line, problem.clone()))
+            .collect();
+        assert_eq!(problems, vec![
+            (3, Problem::DuplicateId(SpanId(2))),
+            (4, problems[1].1.clone()),
+            (5, Problem::MissingParent { id: SpanId(3), parent: SpanId(9) }),
+            (6, Problem::NotOnCpu(SpanId(2))),
+            (7, Problem::UnknownSpan(SpanId(4))),
+            (9, Problem::AlreadyEnded(SpanId(2))),
+        ]);
+        match problems[1].1 {
+            Problem::Malformed(..) => (),
+            ref p => panic!("unexpected {:?}", p),
         }
+
+        let names: Vec<_> = db.tasks.iter().map(|t| db.name(t.name)).collect();
+        assert_eq!(names, vec!["thread", "a", ORPHANS, "c"]);
+        assert_eq!(db.tasks[3].parent, Some(db.tasks[2].id));
+        assert_eq!(db.tasks[2].span, Span { begin: 2_000_000_000, end: 4_000_000_000 });
+
+        let mut report = Vec::new();
+        diagnostics.report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("6 problem(s) loading trace:\n"));
+        assert!(report.contains("line 5: span SpanId(3) has unknown parent SpanId(9)"));
+    }
+
+    #[test]
+    fn test_load_unreadable() {
+        assert!(Database::load_with_rules("/tmp/glviewer_test_missing.log", NameRules::default()).is_err());
+
+        let path = "/tmp/glviewer_test_load_unreadable.log";
+        let mut contents = b"{\"ThreadStart\":{\"name\":\"thread\",\"id\":1,\"ts\":{\"secs\":0,\"nanos\":0}}}\n".to_vec();
+        contents.extend_from_slice(b"{\"ThreadStart\":{\"name\":\"\xff\",\"id\":2,\"ts\":{\"secs\":0,\"nanos\":0}}}\n");
+        contents.extend_from_slice(b"{\"ThreadEnd\":{\"id\":1,\"ts\":{\"secs\":1,\"nanos\":0}}}\n");
+        std::fs::write(path, contents).unwrap();
+
+        // The line that isn't UTF-8 is skipped, and the rest still load.
+        let (db, diagnostics) = Database::load(path);
+        assert_eq!(diagnostics.problems.len(), 1);
+        assert_eq!(diagnostics.problems[0].0, 2);
+        assert!(matches!(diagnostics.problems[0].1, Problem::Malformed(..)));
+        assert_eq!(db.tasks.len(), 1);
+        assert_eq!(db.tasks[0].span, Span { begin: 0, end: 1_000_000_000 });
+    }
+
+    #[test]
+    fn test_follow() {
+        use super::{Follower, Loader};
+        use std::io::Write;
+
+        let full_path = "/tmp/glviewer_test_follow_full.log";
+        let path = "/tmp/glviewer_test_follow.log";
+        write(JsonWriter::new(File::create(full_path).unwrap()));
+        let contents = std::fs::read(full_path).unwrap();
+
+        // Start with the first three lines and half of the fourth.
+        let split = contents.iter().enumerate()
+            .filter(|(_, &b)| b == b'\n')
+            .nth(3)
+            .unwrap().0 - 5;
+        let mut file = File::create(path).unwrap();
+        file.write_all(&contents[..split]).unwrap();
+        file.flush().unwrap();
+
+        let mut db = Database::new();
+        let mut loader = Loader::new();
+        let mut follower = Follower::new(path).unwrap();
+        let roots = follower.poll(&mut loader, &mut db).unwrap();
+        assert_eq!(roots, vec![db.tasks[0].id]);
+        assert_eq!(db.tasks.len(), 2);
+        // The open poll runs up to the latest event.
+        assert_eq!(db.tasks[1].on_cpu, Some(vec![Span { begin: 2_000_000, end: 2_000_000 }]));
+        assert!(follower.poll(&mut loader, &mut db).unwrap().is_empty());
+
+        file.write_all(&contents[split..]).unwrap();
+        file.flush().unwrap();
+        assert_eq!(follower.poll(&mut loader, &mut db).unwrap(), vec![db.tasks[0].id]);
+        assert!(loader.diagnostics.is_empty());
+
+        let (full, _) = Database::load(full_path);
+        assert_eq!(format!("{:?}", db.tasks), format!("{:?}", full.tasks));
+        assert_eq!(db.parks(db.tasks[1].id).len(), 1);
+
+        // The writer can stop partway through a character.
+        let line = "{\"ThreadStart\":{\"name\":\"th\u{e9}\",\"id\":9,\"ts\":{\"secs\":1,\"nanos\":0}}}\n";
+        let split = line.find('\u{e9}').unwrap() + 1;
+        file.write_all(&line.as_bytes()[..split]).unwrap();
+        file.flush().unwrap();
+        assert!(follower.poll(&mut loader, &mut db).unwrap().is_empty());
+        file.write_all(&line.as_bytes()[split..]).unwrap();
+        file.flush().unwrap();
+        assert_eq!(follower.poll(&mut loader, &mut db).unwrap().len(), 1);
+        assert_eq!(db.name(db.tasks.last().unwrap().name), "th\u{e9}");
+        assert!(loader.diagnostics.is_empty());
     }
 }
@@ -0,0 +1,93 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use cyclotron_backend::SpanId;
+
+/// Something wrong with a trace that `Database::load_with_rules` had to work around.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    Malformed(String),
+    DuplicateId(SpanId),
+    MissingParent { id: SpanId, parent: SpanId },
+    UnknownSpan(SpanId),
+    UnknownThread(SpanId),
+    NotAsync(SpanId),
+    AlreadyOnCpu(SpanId),
+    NotOnCpu(SpanId),
+    AlreadyEnded(SpanId),
+}
+
+impl Problem {
+    fn kind(&self) -> &'static str {
+        match self {
+            Problem::Malformed(..) => "malformed event",
+            Problem::DuplicateId(..) => "duplicate span id",
+            Problem::MissingParent { .. } => "missing parent",
+            Problem::UnknownSpan(..) => "unknown span",
+            Problem::UnknownThread(..) => "unknown thread",
+            Problem::NotAsync(..) => "poll of a non-async span",
+            Problem::AlreadyOnCpu(..) => "OnCPU while already on CPU",
+            Problem::NotOnCpu(..) => "OffCPU without OnCPU",
+            Problem::AlreadyEnded(..) => "span ended twice",
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::Malformed(e) => write!(f, "couldn't parse event, skipped it: {}", e),
+            Problem::DuplicateId(id) => write!(f, "span {:?} started again, dropped the new start", id),
+            Problem::MissingParent { id, parent } => {
+                write!(f, "span {:?} has unknown parent {:?}, attached it to {}", id, parent, ORPHANS)
+            }
+            Problem::UnknownSpan(id) => write!(f, "event for unknown span {:?}, dropped it", id),
+            Problem::UnknownThread(id) => write!(f, "poll on unknown thread {:?}, ignored the thread", id),
+            Problem::NotAsync(id) => write!(f, "span {:?} isn't async, dropped its poll", id),
+            Problem::AlreadyOnCpu(id) => write!(f, "span {:?} was already on CPU, dropped the OnCPU", id),
+            Problem::NotOnCpu(id) => write!(f, "span {:?} wasn't on CPU, dropped the OffCPU", id),
+            Problem::AlreadyEnded(id) => write!(f, "span {:?} had already ended, dropped the end", id),
+        }
+    }
+}
+
+/// Name of the synthetic root task that spans with missing parents are attached to.
+pub const ORPHANS: &str = "<orphans>";
+
+/// Every problem found while loading a trace, with the line it was on (or the index of the event,
+/// for formats that don't have lines).
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    pub problems: Vec<(usize, Problem)>,
+}
+
+impl Diagnostics {
+    pub fn push(&mut self, line: usize, problem: Problem) {
+        self.problems.push((line, problem));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Write a count of each kind of problem followed by every problem in trace order.
+    pub fn report(&self, mut out: impl Write) -> io::Result<()> {
+        writeln!(out, "{} problem(s) loading trace:", self.problems.len())?;
+
+        let mut kinds: Vec<(&str, usize)> = Vec::new();
+        for (_, problem) in &self.problems {
+            match kinds.iter_mut().find(|(kind, _)| *kind == problem.kind()) {
+                Some((_, count)) => *count += 1,
+                None => kinds.push((problem.kind(), 1)),
+            }
+        }
+        for (kind, count) in kinds {
+            writeln!(out, "  {:>6} {}", count, kind)?;
+        }
+
+        for (line, problem) in &self.problems {
+            writeln!(out, "  line {}: {}", line, problem)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+
+use cyclotron_backend::Logger;
+use cyclotron_backend::chrome::ChromeWriter;
+use regex::Regex;
+
+use crate::db::{read_records, Database, Span};
+use crate::diagnostics::{Diagnostics, Problem};
+use crate::flame::{overlap, union};
+
+/// Convert a trace to Chrome's Trace Event Format, skipping any events we can't read and returning
+/// them as problems.  It's only an error if the trace can't be opened or the output created.
+pub fn chrome(trace: &str, output: &str) -> Result<Diagnostics, String> {
+    let records = read_records(trace)?;
+    let file = File::create(output).map_err(|e| format!("{}: {}", output, e))?;
+    let mut writer = ChromeWriter::new(file);
+    let mut diagnostics = Diagnostics::default();
+    for (line, event) in records {
+        match event {
+            Ok(event) => writer.write(event),
+            Err(e) => diagnostics.push(line, Problem::Malformed(e)),
+        }
+    }
+    writer.flush();
+    Ok(diagnostics)
+}
+
+/// What to weigh folded stacks by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Weight {
+    // Time spans were running: polled, for async spans.  Threads only run inside their spans, as
+    // far as we know, so their own time doesn't count.
+    OnCpu,
+    Wall,
+}
+
+/// Write folded stacks for flamegraph.pl or inferno: a line per call path, from the thread down
+/// to the span, with the time spent in the path's own spans and not in their children's.  Only
+/// time inside `window` counts, and if there's a `grep`, only spans under one whose name matches.
+/// Weighed by on-CPU time, a thread's time outside of its spans is left out as idle.
+pub fn folded(db: &Database, weight: Weight, window: Span, grep: Option<&Regex>, mut out: impl Write) -> io::Result<()> {
+    let clip = |span: &Span| Span {
+        begin: std::cmp::max(span.begin, window.begin),
+        end: std::cmp::min(span.end, window.end),
+    };
+    let spans: Vec<Vec<Span>> = db.tasks.iter()
+        .map(|task| {
+            let spans = match (&task.on_cpu, weight) {
+                (Some(polls), Weight::OnCpu) => polls.iter().map(clip).collect(),
+                (None, Weight::OnCpu) if task.parent.is_none() => vec![],
+                _ => vec![clip(&task.span)],
+            };
+            spans.into_iter().filter(|span| span.begin < span.end).collect()
+        })
+        .collect();
+    let mut children = vec![vec![]; db.tasks.len()];
+    for task in &db.tasks {
+        if let Some(parent) = task.parent {
+            children[parent.0 as usize].extend(spans[task.id.0 as usize].iter().cloned());
+        }
+    }
+
+    // Parents come before their children, so we can build on their stacks.
+    let mut stacks: Vec<String> = Vec::with_capacity(db.tasks.len());
+    let mut matches = Vec::with_capacity(db.tasks.len());
+    let mut weights = BTreeMap::new();
+    for task in &db.tasks {
+        let index = task.id.0 as usize;
+        let parent = task.parent.map(|parent| parent.0 as usize);
+        let matched = parent.is_some_and(|parent| matches[parent])
+            || grep.map_or(true, |grep| grep.is_match(db.name(task.full_name)));
+        matches.push(matched);
+        // Frames are separated by semicolons, and the weight by the last space.
+        let frame = db.name(task.name).replace(';', ",").replace('\n', " ");
+        let stack = match parent {
+            Some(parent) => format!("{};{}", stacks[parent], frame),
+            None => frame,
+        };
+
+        let own: u64 = spans[index].iter().map(|span| span.end - span.begin).sum();
+        let children = union(std::mem::take(&mut children[index]));
+        let own = own - overlap(&spans[index], &children);
+        if matched && own > 0 {
+            *weights.entry(stack.clone()).or_insert(0) += own;
+        }
+        stacks.push(stack);
+    }
+    for (stack, weight) in weights {
+        writeln!(out, "{} {}", stack, weight)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{folded, Weight};
+    use crate::db::{Database, Span};
+    use cyclotron_backend::json::JsonWriter;
+    use regex::Regex;
+
+    #[test]
+    fn test_folded() {
+        let db = crate::db::tests::load_fixture();
+        let everything = Span { begin: 0, end: u64::MAX };
+        let fold = |weight, window, grep: Option<&str>| {
+            let mut out = vec![];
+            let grep = grep.map(|grep| Regex::new(grep).unwrap());
+            folded(&db, weight, window, grep.as_ref(), &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        // `RemoteAdd` was polled for 2ms and `sync` ran for 2ms, and the thread was otherwise idle.
+        assert_eq!(fold(Weight::OnCpu, everything, None),
+            "thread;RemoteAdd 2000000\nthread;sync 2000000\n");
+        // `RemoteAdd` was around from 1ms to 8ms, covering `sync`.
+        assert_eq!(fold(Weight::Wall, everything, None),
+            "thread 2000000\nthread;RemoteAdd 7000000\nthread;sync 2000000\n");
+        assert_eq!(fold(Weight::OnCpu, everything, Some("sync")), "thread;sync 2000000\n");
+        assert_eq!(fold(Weight::OnCpu, Span { begin: 0, end: 3_000_000 }, None),
+            "thread;RemoteAdd 1000000\n");
+    }
+
+    #[test]
+    fn test_folded_idle_thread() {
+        use cyclotron_backend::{Logger, SpanId, TraceEvent};
+        use std::time::Duration;
+
+        let path = "/tmp/glviewer_test_folded_idle.log";
+        let mut logger = JsonWriter::new(std::fs::File::create(path).unwrap());
+        logger.write(TraceEvent::ThreadStart { name: "idle".into(), id: SpanId(1), ts: Duration::from_millis(0) });
+        logger.write(TraceEvent::ThreadEnd { id: SpanId(1), ts: Duration::from_millis(10) });
+        logger.flush();
+        let (db, _) = Database::load(path);
+
+        let everything = Span { begin: 0, end: u64::MAX };
+        let fold = |weight| {
+            let mut out = vec![];
+            folded(&db, weight, everything, None, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(fold(Weight::OnCpu), "");
+        assert_eq!(fold(Weight::Wall), "idle 10000000\n");
+    }
+}
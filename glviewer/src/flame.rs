@@ -0,0 +1,339 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::db::{NameId, Span, TaskId};
+use crate::layout::{Layout, RowId, ThreadId};
+
+/// A box in the flame graph: everything run on the call path ending in `name`, `depth` levels
+/// below the root, drawn from `left` to `right` out of the graph's width of 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub path: Vec<NameId>,
+    pub depth: usize,
+    pub left: f64,
+    pub right: f64,
+    // Time on the path, including its callees, and time in the path's own spans.
+    pub total: u64,
+    pub self_time: u64,
+}
+
+impl Frame {
+    pub fn name(&self) -> NameId {
+        *self.path.last().unwrap()
+    }
+}
+
+// A call path, merging every task with the same names along its parent chain.
+struct Node {
+    name: NameId,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    self_time: u64,
+    total: u64,
+}
+
+struct Tree {
+    nodes: Vec<Node>,
+    by_path: HashMap<(Option<usize>, NameId), usize>,
+    roots: Vec<usize>,
+    // Which node each task we've seen is in.
+    tasks: HashMap<TaskId, usize>,
+}
+
+impl Tree {
+    fn node(&mut self, layout: &Layout, task: TaskId) -> usize {
+        if let Some(&node) = self.tasks.get(&task) {
+            return node;
+        }
+        let parent = layout.parent(task).map(|parent| self.node(layout, parent));
+        let name = layout.name(task);
+        let nodes = &mut self.nodes;
+        let roots = &mut self.roots;
+        let node = *self.by_path.entry((parent, name)).or_insert_with(|| {
+            let node = nodes.len();
+            nodes.push(Node { name, parent, children: vec![], self_time: 0, total: 0 });
+            match parent {
+                Some(parent) => nodes[parent].children.push(node),
+                None => roots.push(node),
+            }
+            node
+        });
+        self.tasks.insert(task, node);
+        node
+    }
+
+    fn path(&self, mut node: usize) -> Vec<NameId> {
+        let mut path = vec![self.nodes[node].name];
+        while let Some(parent) = self.nodes[node].parent {
+            path.push(self.nodes[parent].name);
+            node = parent;
+        }
+        path.reverse();
+        path
+    }
+
+    // Sum up totals from the leaves, which come after their parents.
+    fn total(&mut self) {
+        for node in (0..self.nodes.len()).rev() {
+            self.nodes[node].total += self.nodes[node].self_time;
+            if let Some(parent) = self.nodes[node].parent {
+                self.nodes[parent].total += self.nodes[node].total;
+            }
+        }
+    }
+
+    fn find(&self, path: &[NameId]) -> Option<usize> {
+        let (first, rest) = path.split_first()?;
+        let mut node = *self.by_path.get(&(None, *first))?;
+        for name in rest {
+            node = *self.by_path.get(&(Some(node), *name))?;
+        }
+        Some(node)
+    }
+}
+
+/// How much of `spans` overlaps `within`, which are both sorted and don't overlap themselves, so
+/// we can walk through them together.
+pub fn overlap(spans: &[Span], within: &[Span]) -> u64 {
+    let mut res = 0;
+    let (mut i, mut j) = (0, 0);
+    while i < spans.len() && j < within.len() {
+        let begin = std::cmp::max(spans[i].begin, within[j].begin);
+        let end = std::cmp::min(spans[i].end, within[j].end);
+        if begin < end {
+            res += end - begin;
+        }
+        // Whichever ends first can't overlap anything after the other.
+        if spans[i].end < within[j].end {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    res
+}
+
+/// The merged union of some spans, sorted.
+pub fn union(mut spans: Vec<Span>) -> Vec<Span> {
+    spans.sort();
+    let mut res: Vec<Span> = vec![];
+    for span in spans {
+        match res.last_mut() {
+            Some(last) if span.begin <= last.end => last.end = std::cmp::max(last.end, span.end),
+            _ => res.push(span),
+        }
+    }
+    res
+}
+
+/// The flame graph of what ran in `span` in the rows we're showing, as an icicle with the threads
+/// at the top, or with the call path `root` at the top if we've zoomed in on it.  A span's self
+/// time is the time it was running (polled, for async spans) with none of its children running
+/// inside it.  Callees are ordered by total time, longest first.
+pub fn frames(filter: &HashSet<(ThreadId, RowId)>, span: Span, root: &[NameId], layout: &Layout) -> Vec<Frame> {
+    // Where each task was running in the window.
+    let mut running: HashMap<TaskId, Vec<Span>> = HashMap::new();
+    for (tid, t) in layout.threads.iter().enumerate() {
+        for (rid, r) in t.rows.iter().enumerate() {
+            if !filter.contains(&(ThreadId(tid), RowId(rid))) {
+                continue;
+            }
+            for (index, &task) in r.fore.tasks.iter().enumerate() {
+                // Collapsed tasks are drawn again in their summary rows.
+                if layout.location(task) != Some((ThreadId(tid), RowId(rid))) {
+                    continue;
+                }
+                let begin = std::cmp::max(r.fore.begins[index], span.begin);
+                let end = std::cmp::min(r.fore.ends[index], span.end);
+                if begin < end {
+                    running.entry(task).or_default().push(Span { begin, end });
+                }
+            }
+        }
+    }
+
+    for spans in running.values_mut() {
+        spans.sort();
+    }
+
+    let mut tree = Tree { nodes: vec![], by_path: HashMap::new(), roots: vec![], tasks: HashMap::new() };
+    let mut tasks: Vec<_> = running.keys().cloned().collect();
+    tasks.sort();
+    for &task in &tasks {
+        tree.node(layout, task);
+    }
+    for &task in &tasks {
+        let node = tree.tasks[&task];
+        let spans = &running[&task];
+        tree.nodes[node].self_time += spans.iter().map(|span| span.end - span.begin).sum::<u64>();
+    }
+    // Children can overlap each other (async ones, say), so each parent loses the time any of them
+    // was running, counted once.
+    let mut children: HashMap<TaskId, Vec<Span>> = HashMap::new();
+    for &task in &tasks {
+        if let Some(parent) = layout.parent(task).filter(|parent| running.contains_key(parent)) {
+            children.entry(parent).or_default().extend_from_slice(&running[&task]);
+        }
+    }
+    for (parent, spans) in children {
+        let nested = overlap(&union(spans), &running[&parent]);
+        let parent_node = tree.tasks[&parent];
+        tree.nodes[parent_node].self_time = tree.nodes[parent_node].self_time.saturating_sub(nested);
+    }
+    tree.total();
+
+    let tops = if root.is_empty() {
+        tree.roots.clone()
+    } else {
+        match tree.find(root) {
+            Some(node) => vec![node],
+            None => return vec![],
+        }
+    };
+    let mut res = vec![];
+    let sum: u64 = tops.iter().map(|&node| tree.nodes[node].total).sum();
+    let mut stack = vec![];
+    let mut left = 0.0;
+    for &node in &tops {
+        let width = tree.nodes[node].total as f64 / sum as f64;
+        stack.push((node, 0, left, left + width));
+        left += width;
+    }
+    while let Some((node, depth, left, right)) = stack.pop() {
+        if right <= left {
+            continue;
+        }
+        let n = &tree.nodes[node];
+        res.push(Frame {
+            path: tree.path(node),
+            depth,
+            left,
+            right,
+            total: n.total,
+            self_time: n.self_time,
+        });
+        let mut children = n.children.clone();
+        children.sort_by_key(|&child| std::cmp::Reverse(tree.nodes[child].total));
+        let mut child_left = left;
+        for child in children {
+            let width = (right - left) * tree.nodes[child].total as f64 / n.total as f64;
+            stack.push((child, depth + 1, child_left, child_left + width));
+            child_left += width;
+        }
+    }
+    res.sort_by(|a, b| (a.depth, a.left).partial_cmp(&(b.depth, b.left)).unwrap());
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::frames;
+    use crate::db::{Database, Span};
+    use crate::layout::{Layout, RowId, ThreadId};
+    use cyclotron_backend::{AsyncOutcome, Logger, SpanId, TraceEvent};
+    use cyclotron_backend::json::JsonWriter;
+    use serde_json::Value;
+    use std::collections::HashSet;
+    use std::fs::File;
+    use std::time::Duration;
+
+    #[test]
+    fn test_overlap() {
+        let spans = |spans: &[(u64, u64)]| -> Vec<Span> {
+            spans.iter().map(|&(begin, end)| Span { begin, end }).collect()
+        };
+        let within = spans(&[(0, 4), (6, 10), (12, 20)]);
+        assert_eq!(super::overlap(&spans(&[(2, 7), (8, 9), (10, 13), (19, 30)]), &within), 2 + 1 + 1 + 1 + 1);
+        assert_eq!(super::overlap(&spans(&[(4, 6), (10, 12)]), &within), 0);
+        assert_eq!(super::overlap(&spans(&[(0, 30)]), &within), 16);
+        assert_eq!(super::overlap(&[], &within), 0);
+    }
+
+    #[test]
+    fn test_frames() {
+        let path = "/tmp/glviewer_test_flame.json";
+        std::fs::write(path, r#"[
+            {"ph": "X", "name": "outer", "ts": 0, "dur": 10, "pid": 1, "tid": 7},
+            {"ph": "X", "name": "inner", "ts": 0, "dur": 4, "pid": 1, "tid": 7},
+            {"ph": "X", "name": "call", "ts": 1, "dur": 1, "pid": 1, "tid": 7},
+            {"ph": "X", "name": "inner", "ts": 5, "dur": 2, "pid": 1, "tid": 7},
+            {"ph": "X", "name": "outer", "ts": 12, "dur": 4, "pid": 1, "tid": 7}
+        ]"#).unwrap();
+        let (db, _) = Database::load(path);
+        let layout = Layout::new(&db);
+        let mut filter = HashSet::new();
+        for (tid, t) in layout.threads.iter().enumerate() {
+            for rid in 0..t.rows.len() {
+                filter.insert((ThreadId(tid), RowId(rid)));
+            }
+        }
+        let name = |name: &str| db.names().position(|n| n == name).map(|id| crate::db::NameId(id as u32)).unwrap();
+        let summary = |span, root: &[_]| -> Vec<_> {
+            frames(&filter, span, root, &layout).iter()
+                .map(|f| (db.name(f.name()), f.depth, f.total, f.self_time))
+                .collect()
+        };
+
+        // Both `outer`s and both `inner`s are merged, and `outer` only ran itself when neither
+        // `inner` was running.
+        assert_eq!(summary(Span { begin: 0, end: 20_000 }, &[]), vec![
+            ("pid 1 tid 7", 0, 16_000, 2_000),
+            ("outer", 1, 14_000, 8_000),
+            ("inner", 2, 6_000, 5_000),
+            ("call", 3, 1_000, 1_000),
+        ]);
+
+        // Only what ran in the window counts.
+        assert_eq!(summary(Span { begin: 3_000, end: 6_000 }, &[]), vec![
+            ("pid 1 tid 7", 0, 3_000, 0),
+            ("outer", 1, 3_000, 1_000),
+            ("inner", 2, 2_000, 2_000),
+        ]);
+
+        // Zooming in puts the path at the top, across the whole width.
+        let thread = db.name(db.tasks[0].name).to_string();
+        let root = [name(&thread), name("outer"), name("inner")];
+        let zoomed = frames(&filter, Span { begin: 0, end: 20_000 }, &root, &layout);
+        assert_eq!((zoomed[0].depth, zoomed[0].left, zoomed[0].right), (0, 0.0, 1.0));
+        assert_eq!(zoomed[1].right, 1.0 / 6.0);
+        assert!(frames(&filter, Span { begin: 0, end: 20_000 }, &[name("call")], &layout).is_empty());
+    }
+
+    #[test]
+    fn test_frames_overlapping_children() {
+        // Two calls made from `outer` are polled on other threads at the same time.
+        let ts = Duration::from_millis;
+        let path = "/tmp/glviewer_test_flame_overlapping.log";
+        let mut logger = JsonWriter::new(File::create(path).unwrap());
+        for (id, name) in &[(1, "main"), (2, "worker 1"), (3, "worker 2")] {
+            logger.write(TraceEvent::ThreadStart { name: name.to_string(), id: SpanId(*id), ts: ts(0) });
+        }
+        logger.write(TraceEvent::SyncStart { name: "outer".into(), id: SpanId(4), parent_id: SpanId(1), ts: ts(0), metadata: Value::Null });
+        for &(id, thread, begin, end) in &[(5, 2, 2, 6), (6, 3, 4, 8)] {
+            logger.write(TraceEvent::AsyncStart { name: "call".into(), id: SpanId(id), parent_id: SpanId(4), ts: ts(1), metadata: Value::Null });
+            logger.write(TraceEvent::AsyncOnCPU { id: SpanId(id), ts: ts(begin), thread_id: Some(SpanId(thread)) });
+            logger.write(TraceEvent::AsyncOffCPU { id: SpanId(id), ts: ts(end) });
+            logger.write(TraceEvent::AsyncEnd { id: SpanId(id), ts: ts(end), outcome: AsyncOutcome::Success });
+        }
+        logger.write(TraceEvent::SyncEnd { id: SpanId(4), ts: ts(10) });
+        for id in 1..=3 {
+            logger.write(TraceEvent::ThreadEnd { id: SpanId(id), ts: ts(10) });
+        }
+        logger.flush();
+
+        let (db, _) = Database::load(path);
+        let layout = Layout::new(&db);
+        let mut filter = HashSet::new();
+        for (tid, t) in layout.threads.iter().enumerate() {
+            for rid in 0..t.rows.len() {
+                filter.insert((ThreadId(tid), RowId(rid)));
+            }
+        }
+        let summary: Vec<_> = frames(&filter, Span { begin: 0, end: 20_000_000 }, &[], &layout).iter()
+            .map(|f| (db.name(f.name()).to_string(), f.depth, f.total, f.self_time))
+            .collect();
+
+        // `outer` ran itself from 0-2ms and 8-10ms, with the time both calls ran counted once.
+        assert!(summary.contains(&("outer".to_string(), 1, 12_000_000, 4_000_000)));
+        assert!(summary.contains(&("call".to_string(), 2, 8_000_000, 8_000_000)));
+    }
+}
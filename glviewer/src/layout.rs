@@ -1,13 +1,64 @@
 use crate::util::VecDefaultMap;
-use crate::db::{Database, TaskId, Task, Span, NameId, NameIdSet};
-use crate::layout_algorithm::layout;
+use crate::db::{Database, TaskId, Task, Span, NameId};
+use crate::layout_algorithm::{layout, layout_thread};
+use cyclotron_backend::AsyncOutcome;
+use std::collections::HashSet;
 use std::time::Duration;
 
 pub struct Layout {
     pub threads: Vec<Thread>,
+    // Tasks whose descendants are summarized in a single row rather than laid out.
+    collapsed: HashSet<TaskId>,
+    groups: VecDefaultMap<NameId, GroupId>,
+    group_colors: u32,
+    // Where each task ended up, indexed by `TaskId`.
+    locations: Vec<Option<(ThreadId, RowId)>>,
+    // Each task's parent and name, indexed by `TaskId`, so we can tell their call paths.
+    ancestry: Vec<(Option<TaskId>, NameId)>,
+    pub wakeups: Vec<Wakeup>,
+    pub hops: Vec<Hop>,
+}
+
+/// A wakeup's endpoints: from the waking task when it woke the parked task, to when the parked task
+/// next ran.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Wakeup {
+    pub waking: TaskId,
+    pub parked: TaskId,
+    pub from: (ThreadId, RowId, u64),
+    pub to: (ThreadId, RowId, u64),
+}
+
+/// An async task moving between threads: from the thread it was last polled on, when that poll
+/// ended, to the thread it's polled on next, when that poll began.  Both ends are in the rows of
+/// the threads themselves.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Hop {
+    pub task: TaskId,
+    pub from: (ThreadId, RowId, u64),
+    pub to: (ThreadId, RowId, u64),
+}
+
+/// Stand-in for the descendants of a collapsed task, drawn in the row below it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub task: TaskId,
+    pub name: NameId,
+    pub span: Span,
+    // When any of the descendants were running, merged so they don't overlap.
+    pub busy: Vec<Span>,
+    pub count: usize,
+    pub busy_time: u64,
+}
+
+impl Summary {
+    pub fn label(&self) -> String {
+        format!("{} spans, {:?} busy", self.count, Duration::from_nanos(self.busy_time))
+    }
 }
 
 pub struct Thread {
+    pub root: TaskId,
     pub rows: Vec<Row>,
 }
 
@@ -22,7 +73,12 @@ pub struct Row {
     pub fore: Chunk,
     pub back: Chunk,
     pub labels: LabelChunk,
-    pub name_set: NameIdSet,
+    pub summaries: Vec<(String, Span)>,
+    // Async tasks that failed or were cancelled, which are drawn over their usual color.
+    pub errors: Vec<Span>,
+    pub cancellations: Vec<Span>,
+    // Time async tasks spent waiting to be polled after being woken.
+    pub delays: Vec<Span>,
 }
 
 impl Row {
@@ -32,12 +88,14 @@ impl Row {
             fore: Chunk::new(),
             back: Chunk::new(),
             labels: LabelChunk::default(),
-            name_set: NameIdSet::new(),
+            summaries: Vec::new(),
+            errors: Vec::new(),
+            cancellations: Vec::new(),
+            delays: Vec::new(),
         }
     }
 
     pub fn add(&mut self, task: &Task) {
-        self.name_set.insert(task.name);
         if let Some(on_cpu) = task.on_cpu.as_ref() {
             self.back.add(task.span, task.name, task.id);
             assert!(!self.fore.has_overlap(task.span));
@@ -50,6 +108,25 @@ impl Row {
             assert!(!self.back.has_overlap(task.span));
         }
         self.labels.add(task);
+        match task.outcome {
+            Some(AsyncOutcome::Error(..)) => self.errors.push(task.span),
+            Some(AsyncOutcome::Cancelled) => self.cancellations.push(task.span),
+            _ => (),
+        }
+    }
+
+    pub fn has_outcomes(&self) -> bool {
+        !self.errors.is_empty() || !self.cancellations.is_empty()
+    }
+
+    // Summaries are drawn like an async task, with its descendants' busy time in the foreground,
+    // and belong to the collapsed task so selecting one selects it.
+    pub fn add_summary(&mut self, summary: &Summary) {
+        self.back.add(summary.span, summary.name, summary.task);
+        for span in &summary.busy {
+            self.fore.add(*span, summary.name, summary.task);
+        }
+        self.summaries.push((summary.label(), summary.span));
     }
 }
 
@@ -69,12 +146,12 @@ impl LabelChunk {
         if index == self.ends.len() {
             self.begins.push(task.span.begin);
             self.ends.push(task.span.end);
-            self.names.push(task.name);
+            self.names.push(task.full_name);
         } else {
             assert!(self.begins[index] >= task.span.end);
             self.begins.insert(index, task.span.begin);
             self.ends.insert(index, task.span.end);
-            self.names.insert(index, task.name);
+            self.names.insert(index, task.full_name);
         }
     }
 
@@ -210,29 +287,182 @@ pub struct LabelListKey(pub ThreadId, pub RowId);
 
 impl Layout {
     pub fn new(db: &Database) -> Layout {
-        let mut threads = layout(db);
+        let collapsed = HashSet::new();
+        let mut layout = Layout {
+            threads: layout(db, &collapsed),
+            collapsed,
+            groups: VecDefaultMap::new(),
+            group_colors: 1,
+            locations: Vec::new(),
+            ancestry: Vec::new(),
+            wakeups: Vec::new(),
+            hops: Vec::new(),
+        };
         let mut tasks_by_name: VecDefaultMap<NameId, usize> = VecDefaultMap::new();
         for task in &db.tasks {
             *tasks_by_name.entry(task.name) += 1;
         }
-        let mut table = VecDefaultMap::new();
-        let mut group_colors = 1;
         for (name, count) in &tasks_by_name {
             if *count > 0 {
-                group_colors += 1;
-                *table.entry(name) = GroupId(group_colors);
+                layout.group(name);
             }
         }
-        for thread in &mut threads {
-            for row in &mut thread.rows {
-                for chunk in &mut [&mut row.back, &mut row.fore] {
-                    for name in &chunk.names {
-                        chunk.groups.push(*table.get(*name));
-                    }
+        for index in 0..layout.threads.len() {
+            layout.assign_groups(ThreadId(index));
+            layout.locate(db, ThreadId(index));
+        }
+        layout.extend_ancestry(db);
+        layout.compute_wakeups(db);
+        layout.compute_hops(db);
+        layout
+    }
+
+    // Tasks never change parents or names, so we only need to add the new ones.
+    fn extend_ancestry(&mut self, db: &Database) {
+        let known = self.ancestry.len();
+        self.ancestry.extend(db.tasks[known..].iter().map(|task| (task.parent, task.name)));
+    }
+
+    pub fn parent(&self, task: TaskId) -> Option<TaskId> {
+        self.ancestry[task.0 as usize].0
+    }
+
+    pub fn name(&self, task: TaskId) -> NameId {
+        self.ancestry[task.0 as usize].1
+    }
+
+    // Collapsed tasks also appear in their summary row, so a task is located at the first row it
+    // appears in.  Tasks hidden under a collapsed task have no location.
+    fn locate(&mut self, db: &Database, thread: ThreadId) {
+        for &task in db.thread_tasks(self.threads[thread.0].root) {
+            if let Some(location) = self.locations.get_mut(task.0 as usize) {
+                *location = None;
+            }
+        }
+        for (rid, row) in self.threads[thread.0].rows.iter().enumerate() {
+            for &task in row.fore.tasks.iter().chain(&row.back.tasks) {
+                let index = task.0 as usize;
+                if self.locations.len() <= index {
+                    self.locations.resize(index + 1, None);
+                }
+                if self.locations[index].is_none() {
+                    self.locations[index] = Some((thread, RowId(rid)));
                 }
             }
         }
-        Layout { threads }
+    }
+
+    /// Collapse the task's descendants into a summary row, or expand them again, and return the
+    /// thread that changed.
+    pub fn toggle_collapsed(&mut self, db: &Database, task: TaskId) -> Vec<ThreadId> {
+        if !self.collapsed.remove(&task) {
+            self.collapsed.insert(task);
+        }
+        self.update(db, &[db.root(task)])
+    }
+
+    pub fn location(&self, task: TaskId) -> Option<(ThreadId, RowId)> {
+        self.locations.get(task.0 as usize).cloned().flatten()
+    }
+
+    fn compute_wakeups(&mut self, db: &Database) {
+        self.wakeups.clear();
+        for task in &db.tasks {
+            let from = match self.location(task.id) {
+                Some(location) => location,
+                None => continue,
+            };
+            for wake in db.wakes(task.id) {
+                let to = match self.location(wake.parked) {
+                    Some(location) => location,
+                    None => continue,
+                };
+                // Point at the parked task's next poll if it has one, or just the wakeup if not.
+                let parked = db.task(wake.parked);
+                let next_poll = parked.on_cpu.as_ref()
+                    .and_then(|on_cpu| on_cpu.iter().find(|span| span.begin >= wake.nanos))
+                    .map(|span| span.begin);
+                let to_time = next_poll.unwrap_or(wake.nanos);
+                self.wakeups.push(Wakeup {
+                    waking: task.id,
+                    parked: wake.parked,
+                    from: (from.0, from.1, wake.nanos),
+                    to: (to.0, to.1, to_time),
+                });
+            }
+        }
+    }
+
+    fn compute_hops(&mut self, db: &Database) {
+        self.hops.clear();
+        for task in &db.tasks {
+            let polls = match &task.on_cpu {
+                Some(polls) => polls,
+                None => continue,
+            };
+            for pair in db.migrations(task.id).windows(2) {
+                let (from, to) = match (self.location(pair[0].thread), self.location(pair[1].thread)) {
+                    (Some(from), Some(to)) => (from, to),
+                    _ => continue,
+                };
+                let last_poll = polls.iter()
+                    .rev()
+                    .find(|poll| poll.begin < pair[1].nanos)
+                    .map_or(pair[0].nanos, |poll| poll.end);
+                self.hops.push(Hop {
+                    task: task.id,
+                    from: (from.0, from.1, last_poll),
+                    to: (to.0, to.1, pair[1].nanos),
+                });
+            }
+        }
+    }
+
+    fn group(&mut self, name: NameId) -> GroupId {
+        let group_colors = &mut self.group_colors;
+        let group = self.groups.entry(name);
+        if *group == GroupId::default() {
+            *group_colors += 1;
+            *group = GroupId(*group_colors);
+        }
+        *group
+    }
+
+    fn assign_groups(&mut self, thread: ThreadId) {
+        let mut rows = std::mem::take(&mut self.threads[thread.0].rows);
+        for row in &mut rows {
+            for chunk in &mut [&mut row.back, &mut row.fore] {
+                chunk.groups = chunk.names.iter().map(|name| self.group(*name)).collect();
+            }
+        }
+        self.threads[thread.0].rows = rows;
+    }
+
+    /// Lay out the threads with these roots again, adding any that are new, and return the ids of
+    /// the threads that changed.  New threads go at the end rather than in start time order, so
+    /// existing `ThreadId`s stay put.
+    pub fn update(&mut self, db: &Database, roots: &[TaskId]) -> Vec<ThreadId> {
+        let mut changed = Vec::new();
+        for &root in roots {
+            let thread = layout_thread(db, root, &self.collapsed);
+            let index = match self.threads.iter().position(|t| t.root == root) {
+                Some(index) => {
+                    self.threads[index] = thread;
+                    index
+                }
+                None => {
+                    self.threads.push(thread);
+                    self.threads.len() - 1
+                }
+            };
+            self.assign_groups(ThreadId(index));
+            self.locate(db, ThreadId(index));
+            changed.push(ThreadId(index));
+        }
+        self.extend_ancestry(db);
+        self.compute_wakeups(db);
+        self.compute_hops(db);
+        changed
     }
 
     pub fn span_discounting_threads(&self) -> Span {
@@ -284,6 +514,43 @@ impl Layout {
         })
     }
 
+    pub fn iter_summaries<'a>(&'a self) -> impl Iterator<Item=(LabelListKey, impl Iterator<Item=(&'a str, Span)> + 'a)> + 'a {
+        self.threads.iter().enumerate().flat_map(|(tid, t)| {
+            t.rows.iter().enumerate().flat_map(move |(rid, r)| {
+                if !r.summaries.is_empty() {
+                    let summaries = r.summaries.iter().map(|(label, span)| (label.as_str(), *span));
+                    Some((LabelListKey(ThreadId(tid), RowId(rid)), summaries))
+                } else {
+                    None
+                }
+            })
+        })
+    }
+
+    pub fn iter_delays(&self) -> impl Iterator<Item=(LabelListKey, &[Span])> {
+        self.threads.iter().enumerate().flat_map(|(tid, t)| {
+            t.rows.iter().enumerate().flat_map(move |(rid, r)| {
+                if r.delays.is_empty() {
+                    None
+                } else {
+                    Some((LabelListKey(ThreadId(tid), RowId(rid)), &r.delays[..]))
+                }
+            })
+        })
+    }
+
+    pub fn iter_outcomes(&self) -> impl Iterator<Item=(LabelListKey, &[Span], &[Span])> {
+        self.threads.iter().enumerate().flat_map(|(tid, t)| {
+            t.rows.iter().enumerate().flat_map(move |(rid, r)| {
+                if r.has_outcomes() {
+                    Some((LabelListKey(ThreadId(tid), RowId(rid)), &r.errors[..], &r.cancellations[..]))
+                } else {
+                    None
+                }
+            })
+        })
+    }
+
     pub fn span_count(&self) -> usize {
         let mut sum = 0;
 
@@ -334,3 +601,64 @@ fn test_has_overlap() {
     assert_eq!(c.begins, vec![1, 2, 3, 10]);
     assert_eq!(c.ends,   vec![2, 3, 5, 15]);
 }
+
+#[test]
+fn test_wakeups() {
+    let db = crate::db::tests::load_fixture();
+    let layout = Layout::new(&db);
+
+    // The sync span wakes the async task halfway through, and it next runs at 7ms.
+    let (thread, async_row) = layout.location(TaskId(1)).unwrap();
+    let (_, sync_row) = layout.location(TaskId(2)).unwrap();
+    assert_ne!(async_row, sync_row);
+    assert_eq!(layout.wakeups, vec![Wakeup {
+        waking: TaskId(2),
+        parked: TaskId(1),
+        from: (thread, sync_row, 5_000_000),
+        to: (thread, async_row, 7_000_000),
+    }]);
+}
+
+#[test]
+fn test_hops() {
+    use cyclotron_backend::{Logger, SpanId, TraceEvent};
+    use cyclotron_backend::json::JsonWriter;
+
+    let path = "/tmp/glviewer_test_hops.log";
+    let ts = Duration::from_millis;
+    let mut logger = JsonWriter::new(std::fs::File::create(path).unwrap());
+    for event in vec![
+        TraceEvent::ThreadStart { name: "a".into(), id: SpanId(1), ts: ts(0) },
+        TraceEvent::ThreadStart { name: "b".into(), id: SpanId(2), ts: ts(0) },
+        TraceEvent::AsyncStart {
+            name: "task".into(),
+            id: SpanId(3),
+            parent_id: SpanId(1),
+            ts: ts(1),
+            metadata: serde_json::Value::Null,
+        },
+        TraceEvent::AsyncOnCPU { id: SpanId(3), ts: ts(1), thread_id: Some(SpanId(1)) },
+        TraceEvent::AsyncOffCPU { id: SpanId(3), ts: ts(2) },
+        TraceEvent::AsyncOnCPU { id: SpanId(3), ts: ts(3), thread_id: Some(SpanId(1)) },
+        TraceEvent::AsyncOffCPU { id: SpanId(3), ts: ts(4) },
+        TraceEvent::AsyncOnCPU { id: SpanId(3), ts: ts(6), thread_id: Some(SpanId(2)) },
+        TraceEvent::AsyncOffCPU { id: SpanId(3), ts: ts(7) },
+        TraceEvent::AsyncEnd { id: SpanId(3), ts: ts(7), outcome: cyclotron_backend::AsyncOutcome::Success },
+        TraceEvent::ThreadEnd { id: SpanId(1), ts: ts(8) },
+        TraceEvent::ThreadEnd { id: SpanId(2), ts: ts(8) },
+    ] {
+        logger.write(event);
+    }
+    logger.flush();
+    let (db, _) = Database::load(path);
+    let layout = Layout::new(&db);
+
+    // The task moves from `a`, where its second poll ended at 4ms, to `b` at 6ms.
+    let a = layout.location(TaskId(0)).unwrap();
+    let b = layout.location(TaskId(1)).unwrap();
+    assert_eq!(layout.hops, vec![Hop {
+        task: TaskId(2),
+        from: (a.0, a.1, 4_000_000),
+        to: (b.0, b.1, 6_000_000),
+    }]);
+}
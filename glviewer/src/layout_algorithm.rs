@@ -1,6 +1,6 @@
 use crate::db::{Database, TaskId, Span};
-use super::layout::{Thread, Row};
-use std::collections::{BTreeSet, HashMap, VecDeque};
+use super::layout::{Thread, Row, Summary};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::ops::Bound;
 use std::time::Instant;
 
@@ -87,11 +87,98 @@ impl LocalLayout {
 //
 // The second pass then takes these "local layouts" and then computes a "global" layout
 // that matches tasks to rows.
-pub fn layout(db: &Database) -> Vec<Thread> {
+//
+// Descendants of `collapsed` tasks aren't laid out at all, and instead get a single summary row
+// underneath the collapsed task.
+pub fn layout(db: &Database, collapsed: &HashSet<TaskId>) -> Vec<Thread> {
     let start = Instant::now();
 
-    let mut children_by_task = HashMap::new();
+    let mut roots = vec![];
     for task in &db.tasks {
+        if task.parent.is_none() {
+            roots.push((task.span.begin, task.id));
+        }
+    }
+    roots.sort();
+
+    let threads = roots.into_iter().map(|(_, root)| layout_thread(db, root, collapsed)).collect();
+    println!("Layout for {} tasks took {:?}", db.tasks.len(), start.elapsed());
+    threads
+}
+
+// Summarize the descendants of a collapsed task, clamped to its span so the summary stays within
+// its bounding box.
+fn summarize(db: &Database, task_id: TaskId, descendants: &[TaskId]) -> Summary {
+    let task = db.task(task_id);
+    let clamp = |span: Span| Span {
+        begin: std::cmp::max(span.begin, task.span.begin),
+        end: std::cmp::max(std::cmp::min(span.end, task.span.end), task.span.begin),
+    };
+
+    let mut busy = vec![];
+    let mut extent: Option<Span> = None;
+    for &child in descendants {
+        let child = db.task(child);
+        extent = Some(match extent {
+            Some(e) => Span { begin: std::cmp::min(e.begin, child.span.begin), end: std::cmp::max(e.end, child.span.end) },
+            None => child.span,
+        });
+        match &child.on_cpu {
+            Some(on_cpu) => busy.extend(on_cpu.iter().cloned()),
+            None => busy.push(child.span),
+        }
+    }
+
+    // Merge overlapping busy spans, since nested spans run at the same time as their parents.
+    busy.sort();
+    let mut merged: Vec<Span> = vec![];
+    for span in busy {
+        let span = clamp(span);
+        match merged.last_mut() {
+            Some(last) if span.begin <= last.end => last.end = std::cmp::max(last.end, span.end),
+            _ => merged.push(span),
+        }
+    }
+    merged.retain(|span| span.begin < span.end);
+
+    Summary {
+        task: task_id,
+        name: task.name,
+        span: clamp(extent.unwrap()),
+        count: descendants.len(),
+        busy_time: merged.iter().map(|span| span.end - span.begin).sum(),
+        busy: merged,
+    }
+}
+
+// Lay out a single thread, which doesn't depend on any of the others.
+pub fn layout_thread(db: &Database, root: TaskId, collapsed: &HashSet<TaskId>) -> Thread {
+    // Find the tasks hidden under a collapsed task, keyed by the outermost collapsed task they're
+    // under.  Parents always come before their children.
+    let mut tasks = vec![];
+    let mut hidden_under: HashMap<TaskId, TaskId> = HashMap::new();
+    let mut descendants: HashMap<TaskId, Vec<TaskId>> = HashMap::new();
+    for &task_id in db.thread_tasks(root) {
+        let owner = db.task(task_id).parent.and_then(|parent| {
+            hidden_under.get(&parent).cloned()
+                .or_else(|| if collapsed.contains(&parent) { Some(parent) } else { None })
+        });
+        match owner {
+            Some(owner) => {
+                hidden_under.insert(task_id, owner);
+                descendants.entry(owner).or_default().push(task_id);
+            }
+            None => tasks.push(task_id),
+        }
+    }
+    let summaries: HashMap<TaskId, Summary> = descendants.iter()
+        .map(|(&task_id, descendants)| (task_id, summarize(db, task_id, descendants)))
+        .collect();
+    let tasks = &tasks;
+
+    let mut children_by_task = HashMap::new();
+    for &task_id in tasks {
+        let task = db.task(task_id);
         if let Some(parent) = task.parent {
             children_by_task.entry(parent)
                 .or_insert_with(Vec::new)
@@ -104,16 +191,11 @@ pub fn layout(db: &Database) -> Vec<Thread> {
     }
 
     let mut leaves = VecDeque::new();
-    let mut roots = vec![];
-    for task in &db.tasks {
-        if !children_by_task.contains_key(&task.id) {
-            leaves.push_back(task.id);
-        }
-        if task.parent.is_none() {
-            roots.push((task.span.begin, task.id));
+    for &task_id in tasks {
+        if !children_by_task.contains_key(&task_id) {
+            leaves.push_back(task_id);
         }
     }
-    roots.sort();
 
     // First, start with all of the leaves, which have no children. Process the task tree bottom-up,
     // doing computing layout locally.
@@ -124,6 +206,9 @@ pub fn layout(db: &Database) -> Vec<Thread> {
     while let Some(task_id) = queue.pop_front() {
         let task = db.task(task_id);
         let mut layout = LocalLayout::new();
+        if summaries.contains_key(&task_id) {
+            layout.total_height = 2;
+        }
         if let Some(children) = children_by_task.get(&task_id) {
             for &(_, child) in children {
                 let span = db.task(child).span;
@@ -143,36 +228,36 @@ pub fn layout(db: &Database) -> Vec<Thread> {
     }
 
     // Okay, now assemble the local layouts into a single global layout.
-    let mut threads = vec![];
+    let mut thread = Thread { root, rows: vec![] };
+    thread.rows.push(Row::new(true));
+    for _ in 1..local_layouts[&root].total_height {
+        thread.rows.push(Row::new(false));
+    }
 
-    for (_, root) in roots.into_iter() {
-        let mut thread = Thread { rows: vec![] };
-        thread.rows.push(Row::new(true));
-        for _ in 1..local_layouts[&root].total_height {
-            thread.rows.push(Row::new(false));
+    let mut stack = vec![(0, root)];
+    while let Some((cur_row, task_id)) = stack.pop() {
+        thread.rows[cur_row].add(db.task(task_id));
+        thread.rows[cur_row].delays.extend(db.scheduling_delays(task_id).map(|delay| delay.span));
+        if let Some(summary) = summaries.get(&task_id) {
+            thread.rows[cur_row + 1].add_summary(summary);
         }
-
-        let mut stack = vec![(0, root)];
-        while let Some((cur_row, task_id)) = stack.pop() {
-            thread.rows[cur_row].add(db.task(task_id));
-            let layout = &local_layouts[&task_id];
-            if let Some(children) = children_by_task.get(&task_id) {
-                for &(_, child_id) in children.iter().rev() {
-                    let child_row = cur_row + layout.children[&child_id].row as usize;
-                    stack.push((child_row, child_id));
-                }
+        let layout = &local_layouts[&task_id];
+        if let Some(children) = children_by_task.get(&task_id) {
+            for &(_, child_id) in children.iter().rev() {
+                let child_row = cur_row + layout.children[&child_id].row as usize;
+                stack.push((child_row, child_id));
             }
         }
-        threads.push(thread);
     }
-    println!("Layout for {} tasks took {:?}", db.tasks.len(), start.elapsed());
-    threads
+    thread
 }
 
 #[cfg(test)]
 mod tests {
     use super::{LayoutRect, layout};
     use crate::db::{Span, NameId, Task, TaskId, Database};
+    use std::collections::HashSet;
+    use serde_json::Value;
 
     #[test]
     fn test_layout_rect() {
@@ -202,54 +287,83 @@ mod tests {
                 id: TaskId(0),
                 parent: None,
                 name: NameId(1),
+                full_name: NameId(1),
                 span: Span {
                     begin: 0,
                     end: 10,
                 },
                 on_cpu: None,
+                metadata: Value::Null,
+                outcome: None,
             },
             Task {
                 id: TaskId(1),
                 parent: None,
                 name: NameId(1),
+                full_name: NameId(1),
                 span: Span {
                     begin: 1,
                     end: 12,
                 },
                 on_cpu: None,
+                metadata: Value::Null,
+                outcome: None,
             },
             Task {
                 id: TaskId(2),
                 parent: Some(TaskId(0)),
                 name: NameId(1),
+                full_name: NameId(1),
                 span: Span {
                     begin: 1,
                     end: 7,
                 },
                 on_cpu: None,
+                metadata: Value::Null,
+                outcome: None,
             },
             Task {
                 id: TaskId(3),
                 parent: Some(TaskId(0)),
                 name: NameId(1),
+                full_name: NameId(1),
                 span: Span {
                     begin: 2,
                     end: 10,
                 },
                 on_cpu: None,
+                metadata: Value::Null,
+                outcome: None,
             },
             Task {
                 id: TaskId(4),
                 parent: Some(TaskId(0)),
                 name: NameId(1),
+                full_name: NameId(1),
                 span: Span {
                     begin: 8,
                     end: 9,
                 },
                 on_cpu: None,
+                metadata: Value::Null,
+                outcome: None,
             },
         ];
         let db = Database::test(tasks);
-        layout(&db);
+        let threads = layout(&db, &HashSet::new());
+        assert_eq!(threads[0].rows.len(), 3);
+
+        // Collapsing the first root puts all three of its children in one summary row.
+        let collapsed = vec![TaskId(0)].into_iter().collect();
+        let threads = layout(&db, &collapsed);
+        assert_eq!(threads[0].rows.len(), 2);
+        let summary = &threads[0].rows[1];
+        assert_eq!(summary.back.tasks, vec![TaskId(0)]);
+        assert_eq!(summary.back.begins, vec![1]);
+        assert_eq!(summary.back.ends, vec![10]);
+        // The children cover 1..10, but the first root ends at 10.
+        assert_eq!(summary.fore.begins, vec![1]);
+        assert_eq!(summary.fore.ends, vec![10]);
+        assert_eq!(summary.summaries, vec![("3 spans, 9ns busy".to_string(), Span { begin: 1, end: 10 })]);
     }
 }
@@ -0,0 +1,98 @@
+use std::io::{self, Write};
+use std::time::Duration;
+
+use crate::db::{Database, Span, TaskId};
+
+/// A poll of an async task that took longer than it should have, blocking `thread` meanwhile.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LongPoll {
+    pub task: TaskId,
+    pub span: Span,
+    pub thread: TaskId,
+}
+
+/// Every poll that took at least `threshold` nanoseconds, longest first.
+pub fn find(db: &Database, threshold: u64) -> Vec<LongPoll> {
+    let mut res = vec![];
+    for task in &db.tasks {
+        let polls = match &task.on_cpu {
+            Some(polls) => polls,
+            None => continue,
+        };
+        for &span in polls {
+            if span.end - span.begin < threshold {
+                continue;
+            }
+            // Polls without a thread of their own are drawn on their task's thread.
+            let thread = db.migrations(task.id).iter()
+                .rev()
+                .find(|migration| migration.nanos <= span.begin)
+                .map_or(db.root(task.id), |migration| migration.thread);
+            res.push(LongPoll { task: task.id, span, thread });
+        }
+    }
+    res.sort_by_key(|poll| (std::cmp::Reverse(poll.span.end - poll.span.begin), poll.span.begin));
+    res
+}
+
+/// The names of `task` and its ancestors, outermost first.
+fn parent_chain(db: &Database, task: TaskId) -> String {
+    let mut names = vec![];
+    let mut next = Some(task);
+    while let Some(task) = next {
+        names.push(db.name(db.task(task).full_name));
+        next = db.task(task).parent;
+    }
+    names.reverse();
+    names.join(" > ")
+}
+
+/// A one line summary of the long polls, for the status bar.
+pub fn describe(db: &Database, polls: &[LongPoll], threshold: u64) -> String {
+    match polls.first() {
+        Some(longest) => format!("{} polls over {:?}, longest {:?} in {}",
+            polls.len(),
+            Duration::from_nanos(threshold),
+            Duration::from_nanos(longest.span.end - longest.span.begin),
+            db.name(db.task(longest.task).full_name)),
+        None => format!("no polls over {:?}", Duration::from_nanos(threshold)),
+    }
+}
+
+/// Print each long poll with when it started relative to `origin`, the thread it blocked and the
+/// chain of spans it was in.
+pub fn write(db: &Database, polls: &[LongPoll], threshold: u64, origin: u64, mut out: impl Write) -> io::Result<()> {
+    writeln!(out, "{} polls over {:?}:", polls.len(), Duration::from_nanos(threshold))?;
+    for poll in polls {
+        writeln!(out, "  {:>12} {:>12}  on {}: {}",
+            format!("{:?}", Duration::from_nanos(poll.span.end - poll.span.begin)),
+            format!("+{:?}", Duration::from_nanos(poll.span.begin.saturating_sub(origin))),
+            db.name(db.task(poll.thread).full_name),
+            parent_chain(db, poll.task))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{describe, find, write};
+    use crate::db::{Span, TaskId};
+
+    #[test]
+    fn test_long_polls() {
+        let db = crate::db::tests::load_fixture();
+
+        // `RemoteAdd` is polled for 1ms twice.
+        let polls = find(&db, 1_000_000);
+        assert_eq!(polls.len(), 2);
+        assert_eq!(polls[0].span, Span { begin: 2_000_000, end: 3_000_000 });
+        assert_eq!(polls[0].thread, TaskId(0));
+        assert!(find(&db, 1_000_001).is_empty());
+        assert_eq!(describe(&db, &polls, 1_000_000), "2 polls over 1ms, longest 1ms in RemoteAdd(/foo)");
+
+        let mut out = vec![];
+        write(&db, &polls, 1_000_000, 0, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().nth(1), Some("           1ms         +2ms  on thread: thread > RemoteAdd(/foo)"));
+    }
+}
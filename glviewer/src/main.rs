@@ -1,20 +1,28 @@
+mod chrome;
+mod critical_path;
 mod db;
+mod diagnostics;
+mod export;
+mod flame;
 mod layout;
 mod layout_algorithm;
+mod long_polls;
 mod render;
 mod view;
+mod search;
+mod stats;
+mod status;
 mod text;
 mod util;
 
-use std::io::Write;
-
-use crate::db::Database;
+use crate::db::{Database, Follower, Loader, NameRules, Span};
+use crate::diagnostics::Diagnostics;
 use crate::layout::Layout;
 use crate::view::{View, SelectionInfo};
 use crate::render::RenderState;
+use crate::search::Search;
 use crate::text::TextCache;
 
-use regex::Regex;
 use glium::{
     glutin,
     Surface,
@@ -29,10 +37,116 @@ struct Args {
     show_framerate: bool,
     #[structopt(default_value="60")]
     target_framerate: f64,
+    /// Leave wakeups out of the status bar
     #[structopt(long)]
     no_wakes_printing: bool,
+    /// Refuse to open a trace with problems, rather than working around them (problems in events
+    /// appended later with --follow are only reported)
+    #[structopt(long)]
+    strict: bool,
+    /// Keep reading events as they're appended to the trace
+    #[structopt(long)]
+    follow: bool,
+    /// Label the time ruler with time since the tracing epoch rather than since the trace started
+    /// (toggle with T)
+    #[structopt(long)]
+    absolute_time: bool,
+    /// Polls that take at least this many milliseconds are long polls, which block their thread
+    /// (highlight them with B)
+    #[structopt(long, default_value = "10")]
+    long_poll_ms: f64,
+    /// Group spans for coloring and profiling by their names rewritten with this rule, written as
+    /// `PATTERN => REPLACEMENT`, rather than by cutting names off at the first `(` or `{` (can be
+    /// given more than once; rules apply in order)
+    #[structopt(long, number_of_values = 1)]
+    simplify: Vec<String>,
+    /// Read simplification rules like --simplify's from a file, one per line, which apply before
+    /// any given with --simplify
+    #[structopt(long)]
+    simplify_rules: Option<String>,
     // grep: Vec<String>,
     // hide_wakeups: Vec<String>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Convert the trace to Chrome's Trace Event Format, for chrome://tracing or Perfetto
+    ExportChrome {
+        output: String,
+    },
+    /// Write folded stacks for flamegraph.pl or inferno, weighted by nanoseconds on CPU
+    ExportFolded {
+        output: String,
+        /// Weigh stacks by wall time rather than time on CPU
+        #[structopt(long)]
+        wall: bool,
+        /// Only count time from this many milliseconds into the trace
+        #[structopt(long)]
+        from_ms: Option<f64>,
+        /// Only count time until this many milliseconds into the trace
+        #[structopt(long)]
+        to_ms: Option<f64>,
+        /// Only include spans under one whose name matches this regex
+        #[structopt(long)]
+        grep: Option<String>,
+    },
+    /// Print the count, durations and on-CPU time of the spans with each name on each thread,
+    /// without opening a window
+    Stats {
+        /// table, csv or json (durations are in nanoseconds for csv and json)
+        #[structopt(long, default_value = "table")]
+        format: stats::Format,
+    },
+    /// Print what the longest span whose name matches a regex spent its time waiting on, following
+    /// wakeups back from its end
+    CriticalPath {
+        pattern: String,
+    },
+    /// Print every poll that took at least --long-poll-ms, longest first, with the thread it
+    /// blocked and the spans it was in
+    LongPolls,
+}
+
+// How often to check for new events when following a trace.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+
+fn report(diagnostics: &Diagnostics, strict: bool) {
+    if !diagnostics.is_empty() {
+        diagnostics.report(std::io::stderr()).unwrap();
+        if strict {
+            std::process::exit(1);
+        }
+    }
+}
+
+// Load the trace, reporting any problems with it, or give up if it can't be opened.
+fn load(args: &Args, rules: NameRules) -> Database {
+    let (db, diagnostics) = Database::load_with_rules(&args.trace, rules).unwrap_or_else(|e| {
+        eprintln!("Can't open trace: {}", e);
+        std::process::exit(1);
+    });
+    report(&diagnostics, args.strict);
+    db
+}
+
+fn name_rules(args: &Args) -> NameRules {
+    let rules = match &args.simplify_rules {
+        Some(path) => NameRules::read(path),
+        None if args.simplify.is_empty() => return NameRules::default(),
+        None => Ok(NameRules::none()),
+    };
+    let rules = rules.and_then(|mut rules| {
+        for rule in &args.simplify {
+            rules.push(rule)?;
+        }
+        Ok(rules)
+    });
+    rules.unwrap_or_else(|e| {
+        eprintln!("Bad simplification rule: {}", e);
+        std::process::exit(1);
+    })
 }
 
 #[derive(Default)]
@@ -46,7 +160,80 @@ struct NavKeys {
 fn main() {
     let args = Args::from_args();
 
-    let db = Database::load(&args.trace);
+    if let Some(Command::ExportChrome { output }) = &args.command {
+        let diagnostics = export::chrome(&args.trace, output).unwrap_or_else(|e| {
+            eprintln!("Can't export trace: {}", e);
+            std::process::exit(1);
+        });
+        if args.strict && !diagnostics.is_empty() {
+            // Don't leave a partial export behind.
+            let _ = std::fs::remove_file(output);
+        }
+        report(&diagnostics, args.strict);
+        return;
+    }
+
+    let rules = name_rules(&args);
+    let long_poll_threshold = (args.long_poll_ms * 1e6) as u64;
+    if let Some(Command::LongPolls) = &args.command {
+        let db = load(&args, rules);
+        let origin = db.tasks.iter().map(|task| task.span.begin).min().unwrap_or(0);
+        let polls = long_polls::find(&db, long_poll_threshold);
+        long_polls::write(&db, &polls, long_poll_threshold, origin, std::io::stdout().lock()).unwrap();
+        return;
+    }
+    if let Some(Command::Stats { format }) = &args.command {
+        let db = load(&args, rules);
+        stats::write(&stats::compute(&db), *format, std::io::stdout().lock()).unwrap();
+        return;
+    }
+    if let Some(Command::ExportFolded { output, wall, from_ms, to_ms, grep }) = &args.command {
+        let db = load(&args, rules);
+        let grep = grep.as_ref().map(|grep| regex::Regex::new(grep).unwrap_or_else(|e| {
+            eprintln!("Bad pattern: {}", e);
+            std::process::exit(1);
+        }));
+        let origin = db.tasks.iter().map(|task| task.span.begin).min().unwrap_or(0);
+        let at = |ms: Option<f64>, default| ms.map_or(default, |ms| origin + (ms * 1e6) as u64);
+        let window = Span { begin: at(*from_ms, 0), end: at(*to_ms, u64::MAX) };
+        let weight = if *wall { export::Weight::Wall } else { export::Weight::OnCpu };
+        let out = std::io::BufWriter::new(std::fs::File::create(output).unwrap());
+        export::folded(&db, weight, window, grep.as_ref(), out).unwrap();
+        return;
+    }
+    if let Some(Command::CriticalPath { pattern }) = &args.command {
+        let db = load(&args, rules);
+        let regex = regex::Regex::new(pattern).unwrap_or_else(|e| {
+            eprintln!("Bad pattern: {}", e);
+            std::process::exit(1);
+        });
+        let target = db.tasks.iter()
+            .filter(|task| regex.is_match(db.name(task.full_name)))
+            .max_by_key(|task| (task.span.end - task.span.begin, std::cmp::Reverse(task.id.0)));
+        let target = target.unwrap_or_else(|| {
+            eprintln!("No span matches {:?}", pattern);
+            std::process::exit(1);
+        });
+        let steps = critical_path::compute(&db, target.id);
+        critical_path::write(&db, target.id, &steps, std::io::stdout().lock()).unwrap();
+        return;
+    }
+    let (mut db, mut follow) = if args.follow {
+        let mut follower = Follower::new(&args.trace).unwrap_or_else(|e| {
+            eprintln!("Can't follow {}: {}", args.trace, e);
+            std::process::exit(1);
+        });
+        let mut loader = Loader::with_rules(rules);
+        let mut db = Database::new();
+        follower.poll(&mut loader, &mut db).unwrap_or_else(|e| {
+            eprintln!("Can't read {}: {}", args.trace, e);
+            std::process::exit(1);
+        });
+        report(&std::mem::take(&mut loader.diagnostics), args.strict);
+        (db, Some((follower, loader)))
+    } else {
+        (load(&args, rules), None)
+    };
     let mut layout = Layout::new(&db);
 
     let event_loop = glutin::event_loop::EventLoop::new();
@@ -59,24 +246,32 @@ fn main() {
 
     let text_cache = TextCache::new(&display, db.name_ids_by_name());
     let mut view = View::new(&layout);
+    view.set_absolute_time(args.absolute_time);
     let mut render = RenderState::new(&layout, &display, text_cache);
 
     let target_frame_delta = Duration::from_nanos((1e9 / args.target_framerate) as u64);
 
     let mut click_down_time = None;
-    let mut last_name = None;
+    // What the last command had to say, for the status bar.
+    let mut message = String::new();
+    let mut showing_long_polls = false;
     let mut modifiers = glutin::event::ModifiersState::empty();
     let mut keys = NavKeys::default();
     let mut span_stack = Vec::new();
 
     let mut last_frame = Instant::now();
     let mut frame_rates = Vec::new();
+    let mut last_follow = Instant::now();
 
     enum InputMode {
         Navigate,
         Search(String),
     }
     let mut input_mode = InputMode::Navigate;
+    // The task the details panel is showing.
+    let mut details_task = None;
+    // The last search, for stepping through its matches.
+    let mut search: Option<Search> = None;
 
     event_loop.run(move |event, _, control_flow| {
         let now = Instant::now();
@@ -96,27 +291,103 @@ fn main() {
                 glutin::event::WindowEvent::ReceivedCharacter(ch) => {
                     match &mut input_mode {
                         InputMode::Navigate => {
-                            if ch == '/' {
-                                input_mode = InputMode::Search(String::new());
+                            match ch {
+                                '/' => {
+                                    input_mode = InputMode::Search(String::new());
+                                    search = None;
+                                    showing_long_polls = false;
+                                    view.set_search_box(Some("/".to_string()));
+                                    view.set_highlight(None, &layout);
+                                }
+                                // Show only the tasks that failed or were cancelled, or everything again.
+                                'f' => {
+                                    if search.as_ref().is_some_and(|s| s.is_failures()) {
+                                        search = None;
+                                        message.clear();
+                                    } else {
+                                        let failures = Search::failures(&db);
+                                        message = failures.describe();
+                                        search = Some(failures);
+                                    }
+                                    view.set_filter(search.as_ref().map(|s| s.tasks.clone()), &layout);
+                                }
+                                // Highlight the critical path of the span under the cursor, or clear it.
+                                'c' => {
+                                    showing_long_polls = false;
+                                    match view.selection() {
+                                        Some(SelectionInfo::Span { task, .. }) => {
+                                            let steps = critical_path::compute(&db, task);
+                                            view.set_highlight_spans(steps.iter().map(|step| (step.task, step.span)), false, &layout);
+                                            message = critical_path::describe(&db, task, &steps);
+                                        }
+                                        _ => {
+                                            view.set_highlight(None, &layout);
+                                            message.clear();
+                                        }
+                                    }
+                                }
+                                // Highlight the long polls, or clear them.
+                                'b' | 'B' => {
+                                    showing_long_polls = !showing_long_polls;
+                                    if showing_long_polls {
+                                        let polls = long_polls::find(&db, long_poll_threshold);
+                                        view.set_highlight_spans(polls.iter().map(|poll| (poll.task, poll.span)), true, &layout);
+                                        message = long_polls::describe(&db, &polls, long_poll_threshold);
+                                    } else {
+                                        view.set_highlight(None, &layout);
+                                        message.clear();
+                                    }
+                                }
+                                'l' => {
+                                    view.set_show_delays(!view.show_delays());
+                                    message = format!("scheduling delays: {}", if view.show_delays() { "shown" } else { "hidden" });
+                                }
+                                // Step through the last search's matches in time order.
+                                'n' | 'N' => {
+                                    if let Some(search) = &mut search {
+                                        let from = view.span().begin;
+                                        let next = if ch == 'n' { search.next(from) } else { search.previous(from) };
+                                        if let Some((span, _)) = next {
+                                            view.show(&layout, span);
+                                        }
+                                        message = search.describe();
+                                    }
+                                }
+                                _ => {}
                             }
                         }
                         InputMode::Search(ref mut text) => {
                             if ch == '\r' {
-                                println!("Search {:?}", text);
-                                match Regex::new(&format!(".*{}.*", text)) {
-                                    Ok(regex) => {
-                                        let name_set = db.names_matching_regex(regex);
-                                        println!("matching names: {:?}", name_set.count_len());
-                                        view.set_filter(Some(name_set), &layout);
-                                    }
-                                    Err(e) => {
-                                        println!("error parsing regex: {:?}", e);
-                                    }
+                                // An empty search clears the filter.
+                                if text.is_empty() {
+                                    search = None;
                                 }
+                                message = match &search {
+                                    Some(search) => search.describe(),
+                                    None if !text.is_empty() => format!("invalid regex {:?}", text),
+                                    None => String::new(),
+                                };
+                                view.set_filter(search.as_ref().map(|s| s.tasks.clone()), &layout);
+                                view.set_search_box(None);
                                 input_mode = InputMode::Navigate;
-                            } else {
-                                text.push(ch);
+                                return;
+                            }
+                            match ch {
+                                '\u{8}' | '\u{7f}' => { text.pop(); }
+                                ch if ch.is_control() => return,
+                                ch => text.push(ch),
                             }
+                            // Highlight matches as we type.
+                            let (found, box_text) = match Search::new(&db, text) {
+                                Ok(found) => {
+                                    let box_text = format!("/{}  ({} matches)", text, found.len());
+                                    (Some(found), box_text)
+                                }
+                                Err(..) => (None, format!("/{}  (invalid regex)", text)),
+                            };
+                            view.set_highlight(found.as_ref().filter(|_| !text.is_empty()).map(|s| s.tasks.clone()), &layout);
+                            view.set_search_box(Some(box_text));
+                            search = found;
                         }
                     }
                 }
@@ -154,8 +425,17 @@ fn main() {
                                         view.toggle_mode(&layout);
                                     }
                                 }
+                                glutin::event::VirtualKeyCode::V if pressed => {
+                                    let mode = view.cycle_arrow_mode(&layout);
+                                    message = format!("wakeup and thread hop arrows: {:?}", mode);
+                                }
+                                glutin::event::VirtualKeyCode::T if pressed => {
+                                    view.set_absolute_time(!view.absolute_time());
+                                }
                                 glutin::event::VirtualKeyCode::Escape if pressed => {
-                                    if let Some(span) = span_stack.pop() {
+                                    if view.unzoom_flame(&layout) {
+                                        // Back out of the flame graph before the time window.
+                                    } else if let Some(span) = span_stack.pop() {
                                         view.set_span(&layout, span)
                                     } else {
                                         view.set_span_full(&layout);
@@ -168,6 +448,10 @@ fn main() {
                             match key {
                                 glutin::event::VirtualKeyCode::Escape if pressed => {
                                     input_mode = InputMode::Navigate;
+                                    search = None;
+                                    view.set_search_box(None);
+                                    view.set_highlight(None, &layout);
+                                    message.clear();
                                 }
                                 _ => {}
                             }
@@ -186,6 +470,15 @@ fn main() {
                                 span_stack.push(view.end_drag());
                             } else {
                                 view.cancel_drag();
+                                // A click collapses or expands the span under the cursor, or
+                                // zooms into the flame graph frame under it.
+                                if let Some(SelectionInfo::Span { task, .. }) = view.selection() {
+                                    let threads = layout.toggle_collapsed(&db, task);
+                                    render.update(&layout, &display, &threads);
+                                    view.update(&layout);
+                                } else {
+                                    view.zoom_flame(&layout);
+                                }
                             }
                         },
                     }
@@ -224,6 +517,32 @@ fn main() {
         last_frame = now;
         *control_flow = glutin::event_loop::ControlFlow::WaitUntil(next_frame_time);
 
+        if let Some((follower, loader)) = &mut follow {
+            if now - last_follow >= FOLLOW_INTERVAL {
+                last_follow = now;
+                let name_count = db.name_ids_by_name().len();
+                // Read errors may be passing, so we'll try again next time.
+                let roots = follower.poll(loader, &mut db).unwrap_or_else(|e| {
+                    eprintln!("Can't read {}: {}", args.trace, e);
+                    vec![]
+                });
+                // --strict only applies to what's there when we start, not to a session that's
+                // already open.
+                report(&std::mem::take(&mut loader.diagnostics), false);
+                if !roots.is_empty() {
+                    let threads = layout.update(&db, &roots);
+                    if db.name_ids_by_name().len() != name_count {
+                        // The text cache only has glyphs for the names it started with.
+                        render.text_cache = TextCache::new(&display, db.name_ids_by_name());
+                        render.rebuild(&layout, &display);
+                    } else {
+                        render.update(&layout, &display, &threads);
+                    }
+                    view.update(&layout);
+                }
+            }
+        }
+
         if modifiers == glutin::event::ModifiersState::empty() {
             let elapsed = elapsed.as_secs_f64();
             let factor = 400.0;
@@ -246,50 +565,27 @@ fn main() {
             frame_rates.push(elapsed.as_nanos() as u64);
 
             if frame_rates.len() == 60 {
-                print!("\r\x1b[0Kaverage {:.3} worst {:.3}",
+                message = format!("average {:.3} worst {:.3}",
                     1e9 * frame_rates.len() as f64 / frame_rates.iter().sum::<u64>() as f64,
                     1e9 / *frame_rates.iter().max().unwrap() as f64);
-                std::io::stdout().flush().unwrap();
                 frame_rates.clear();
             }
-
-        } else if let Some(selected) = view.selection() {
-            if last_name != Some(selected) {
-                match selected {
-                    SelectionInfo::Span { name, span, task } => {
-                        println!("start {:?} length {:?} : {}",
-                            Duration::from_nanos(span.begin),
-                            Duration::from_nanos(span.end - span.begin),
-                            db.name(name));
-
-                        if !args.no_wakes_printing {
-                            let parks = db.parks(task);
-                            for wake in parks {
-                                println!("    woken by: {}", db.name(db.task(wake.waking).name));
-                            }
-
-                            let wakes = db.wakes(task);
-                            for wake in wakes {
-                                println!("    wakes: {}", db.name(db.task(wake.parked).name));
-                            }
-                        }
-                    }
-                    SelectionInfo::ProfileName { name, time } => {
-                        println!("time {:?} ({:.2}%) : {}",
-                            Duration::from_nanos(time),
-                            time as f32 / view.span_time() as f32 * 100.0,
-                            db.name(name));
-                    }
-                }
-
-                last_name = Some(selected);
-            }
         }
 
         let mut target = display.draw();
         target.clear_color_and_depth((1.0, 1.0, 1.0, 1.0), 1.0);
 
-        render.draw(&view, &mut target);
+        let selected = match view.selection() {
+            Some(SelectionInfo::Span { task, .. }) => Some(task),
+            _ => None,
+        };
+        if selected != details_task {
+            details_task = selected;
+            view.set_details(selected.map(|task| status::details(&db, task)).unwrap_or_default());
+        }
+
+        let status = status::status_line(&db, &view, !args.no_wakes_printing, &message);
+        render.draw(&view, &display, &mut target, &status);
 
         target.finish().unwrap();
     });
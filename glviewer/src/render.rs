@@ -1,9 +1,9 @@
 use crate::layout::GroupId;
-use crate::view::View;
+use crate::view::{View, Arrow};
 use crate::db::{Span, NameId};
 use crate::util::hsl_to_rgb;
 use std::collections::HashMap;
-use crate::layout::{Layout, BoxListKey, LabelListKey, SpanRange};
+use crate::layout::{Layout, BoxListKey, LabelListKey, SpanRange, ThreadId};
 use crate::text::{TextCache, LabelListData};
 use glium::{
     Surface,
@@ -97,6 +97,86 @@ impl SimpleBoxData {
     }
 }
 
+// Arrow shaft thickness and head size, as fractions of the window.
+const ARROW_WIDTH: f32 = 0.0015;
+const ARROW_HEAD: f32 = 0.008;
+
+// Triangles for a list of arrows, in window coordinates, with the selected ones in a separate range
+// at the end so they can be drawn in a different color.
+struct ArrowData {
+    vertex: VertexBuffer<SimpleBoxVertex>,
+    selected_begin: usize,
+}
+
+impl ArrowData {
+    fn new(display: &Display, arrows: &[Arrow]) -> ArrowData {
+        let mut verts = Vec::new();
+        let mut selected_begin = 0;
+        for &selected in &[false, true] {
+            if selected {
+                selected_begin = verts.len();
+            }
+            for arrow in arrows.iter().filter(|a| a.selected == selected) {
+                let (x0, y0) = arrow.from;
+                let (x1, y1) = arrow.to;
+                let length = ((x1 - x0).powi(2) + (y1 - y0).powi(2)).sqrt();
+                if length == 0.0 {
+                    continue;
+                }
+                let (dx, dy) = ((x1 - x0) / length, (y1 - y0) / length);
+                let (nx, ny) = (-dy, dx);
+                let head = ARROW_HEAD.min(length);
+                let (bx, by) = (x1 - dx * head, y1 - dy * head);
+
+                let w = ARROW_WIDTH / 2.0;
+                let shaft = [
+                    [x0 + nx * w, y0 + ny * w],
+                    [x0 - nx * w, y0 - ny * w],
+                    [bx + nx * w, by + ny * w],
+                    [x0 - nx * w, y0 - ny * w],
+                    [bx + nx * w, by + ny * w],
+                    [bx - nx * w, by - ny * w],
+                ];
+                let h = head / 2.0;
+                let tip = [
+                    [x1, y1],
+                    [bx + nx * h, by + ny * h],
+                    [bx - nx * h, by - ny * h],
+                ];
+                for &position in shaft.iter().chain(&tip) {
+                    verts.push(SimpleBoxVertex { position });
+                }
+            }
+        }
+        ArrowData {
+            vertex: VertexBuffer::new(display, &verts).unwrap(),
+            selected_begin,
+        }
+    }
+
+    fn draw(&self, shaders: &Shaders, params: &DrawParameters, target: &mut Frame, color: Color, selected_color: Color) {
+        let ranges = [
+            (0 .. self.selected_begin, color),
+            (self.selected_begin .. self.vertex.len(), selected_color),
+        ];
+        for (range, color) in ranges.iter().cloned() {
+            if range.start == range.end {
+                continue;
+            }
+            target.draw(
+                self.vertex.slice(range).unwrap(),
+                glium::index::NoIndices(PrimitiveType::TrianglesList),
+                &shaders.simple_box_program,
+                &uniform! {
+                    scale: [1.0f32, 1.0f32],
+                    offset: [0.0f32, 0.0f32],
+                    item_color: [color.r, color.g, color.b, color.a],
+                },
+                params).unwrap();
+        }
+    }
+}
+
 struct BoxListData {
     vertex: VertexBuffer<BoxListVertex>,
     index: IndexBuffer<u32>,
@@ -127,6 +207,10 @@ impl BoxListData {
         }
     }
 
+    fn len(&self) -> usize {
+        self.index.len() / 6
+    }
+
     fn draw(
         &self,
         shaders: &Shaders,
@@ -287,14 +371,95 @@ pub enum DrawCommand {
         key: LabelListKey,
         region: Region,
     },
+    RulerLabels {
+        region: Region,
+    },
+    // The labels for one level of the flame graph.
+    FlameLabels {
+        depth: usize,
+        region: Region,
+    },
+    Arrows,
+    Hops,
+    StatusBar {
+        region: Region,
+    },
+    SearchBox {
+        region: Region,
+    },
+    // Failed and cancelled async tasks in a row.
+    Outcomes {
+        key: LabelListKey,
+        region: Region,
+    },
+    // Time async tasks in a row spent waiting to be polled after being woken.
+    Delays {
+        key: LabelListKey,
+        region: Region,
+    },
+    // Highlighted boxes in a row, drawn over the row's boxes.
+    Highlight {
+        key: BoxListKey,
+        region: Region,
+    },
+    Details {
+        line: usize,
+        region: Region,
+    },
 }
 
+// Boxes for spans that are all drawn in one color.
+fn span_data(display: &Display, spans: &[Span]) -> BoxListData {
+    BoxListData::from_iter(display, spans.iter().map(|&span| (GroupId::default(), NameId(0), span)))
+}
+
+fn outcome_data(display: &Display, errors: &[Span], cancellations: &[Span]) -> (BoxListData, BoxListData) {
+    (span_data(display, errors), span_data(display, cancellations))
+}
+
+// Rebuild a single line of text if it's changed since last frame.  It's laid out across one logical
+// unit, which the caller stretches across its region.
+fn line_data(line: &mut Option<(String, LabelListData)>, text_cache: &TextCache, display: &Display, text: &str) {
+    let stale = match line {
+        Some((previous, _)) => previous != text,
+        None => true,
+    };
+    if stale {
+        let span = Span { begin: 0, end: 1_000_000_000 };
+        let data = text_cache.string_data(display, std::iter::once((text, span)));
+        *line = Some((text.to_string(), data));
+    }
+}
+
+type FlameLabels = Vec<Vec<(NameId, Span)>>;
+
 pub struct RenderState {
     simple_box: SimpleBoxData,
     color_texture: Texture1d,
     shaders: Shaders,
     box_lists: HashMap<BoxListKey, BoxListData>,
     label_lists: HashMap<LabelListKey, LabelListData>,
+    // Failed and cancelled async tasks in each row.
+    outcome_lists: HashMap<LabelListKey, (BoxListData, BoxListData)>,
+    // Scheduling delays in each row.
+    delay_lists: HashMap<LabelListKey, BoxListData>,
+    // Labels for the summary rows of collapsed tasks, which are drawn along with the row's names.
+    summary_lists: HashMap<LabelListKey, LabelListData>,
+    // The ruler's labels change whenever we scroll, so we rebuild them when they're different from
+    // last frame's.
+    ruler_labels: Option<(Vec<(String, Span)>, LabelListData)>,
+    // The flame graph's labels for each level, and their text.
+    flame_labels: Option<(FlameLabels, Vec<LabelListData>)>,
+    // Same for the wakeup arrows, which change when we scroll or select something else, and the
+    // thread hop arrows.
+    arrows: Option<(Vec<Arrow>, ArrowData)>,
+    hops: Option<(Vec<Arrow>, ArrowData)>,
+    // And the status bar, which changes whenever we hover over something else, and the search box.
+    status: Option<(String, LabelListData)>,
+    search_box: Option<(String, LabelListData)>,
+    // Highlighted boxes, which we rebuild when the view's count of changes to them moves on.
+    highlights: Option<(u64, HashMap<BoxListKey, BoxListData>)>,
+    details: Vec<Option<(String, LabelListData)>>,
     pub text_cache: TextCache,
 }
 
@@ -311,6 +476,21 @@ impl RenderState {
             label_lists.insert(key, text_cache.data(display, labels));
         }
 
+        let mut outcome_lists = HashMap::new();
+        for (key, errors, cancellations) in layout.iter_outcomes() {
+            outcome_lists.insert(key, outcome_data(display, errors, cancellations));
+        }
+
+        let mut delay_lists = HashMap::new();
+        for (key, delays) in layout.iter_delays() {
+            delay_lists.insert(key, span_data(display, delays));
+        }
+
+        let mut summary_lists = HashMap::new();
+        for (key, summaries) in layout.iter_summaries() {
+            summary_lists.insert(key, text_cache.string_data(display, summaries));
+        }
+
         let mut colors = Vec::new();
 
         let mut rng = rand::thread_rng();
@@ -332,11 +512,29 @@ impl RenderState {
             shaders: Shaders::new(display),
             box_lists,
             label_lists,
+            outcome_lists,
+            delay_lists,
+            summary_lists,
+            ruler_labels: None,
+            flame_labels: None,
+            arrows: None,
+            hops: None,
+            status: None,
+            search_box: None,
+            highlights: None,
+            details: Vec::new(),
             text_cache,
         }
     }
 
+    /// Replace every buffer, as when the text cache has been replaced.  Cached text indexes into
+    /// the old cache's glyphs, so it's dropped to be made again on the next frame.
     pub fn rebuild(&mut self, layout: &Layout, display: &Display) {
+        self.ruler_labels = None;
+        self.flame_labels = None;
+        self.status = None;
+        self.search_box = None;
+        self.details.clear();
         self.box_lists.clear();
         for (key, items) in layout.iter_box_lists() {
             self.box_lists.insert(key, BoxListData::from_iter(display, items));
@@ -345,10 +543,106 @@ impl RenderState {
         for (key, labels) in layout.iter_labels() {
             self.label_lists.insert(key, self.text_cache.data(display, labels));
         }
+        self.outcome_lists.clear();
+        for (key, errors, cancellations) in layout.iter_outcomes() {
+            self.outcome_lists.insert(key, outcome_data(display, errors, cancellations));
+        }
+        self.delay_lists.clear();
+        for (key, delays) in layout.iter_delays() {
+            self.delay_lists.insert(key, span_data(display, delays));
+        }
+        self.summary_lists.clear();
+        for (key, summaries) in layout.iter_summaries() {
+            self.summary_lists.insert(key, self.text_cache.string_data(display, summaries));
+        }
         self.simple_box = SimpleBoxData::new(display);
     }
 
-    pub fn draw(&self, view: &View, target: &mut Frame) {
+    /// Replace the buffers for just these threads.
+    pub fn update(&mut self, layout: &Layout, display: &Display, threads: &[ThreadId]) {
+        self.box_lists.retain(|key, _| !threads.contains(&key.0));
+        self.label_lists.retain(|key, _| !threads.contains(&key.0));
+        self.summary_lists.retain(|key, _| !threads.contains(&key.0));
+        self.outcome_lists.retain(|key, _| !threads.contains(&key.0));
+        self.delay_lists.retain(|key, _| !threads.contains(&key.0));
+        for (key, items) in layout.iter_box_lists() {
+            if threads.contains(&key.0) {
+                self.box_lists.insert(key, BoxListData::from_iter(display, items));
+            }
+        }
+        for (key, labels) in layout.iter_labels() {
+            if threads.contains(&key.0) {
+                self.label_lists.insert(key, self.text_cache.data(display, labels));
+            }
+        }
+        for (key, summaries) in layout.iter_summaries() {
+            if threads.contains(&key.0) {
+                self.summary_lists.insert(key, self.text_cache.string_data(display, summaries));
+            }
+        }
+        for (key, errors, cancellations) in layout.iter_outcomes() {
+            if threads.contains(&key.0) {
+                self.outcome_lists.insert(key, outcome_data(display, errors, cancellations));
+            }
+        }
+        for (key, delays) in layout.iter_delays() {
+            if threads.contains(&key.0) {
+                self.delay_lists.insert(key, span_data(display, delays));
+            }
+        }
+    }
+
+    pub fn draw(&mut self, view: &View, display: &Display, target: &mut Frame, status: &str) {
+        line_data(&mut self.status, &self.text_cache, display, status);
+        if let Some(text) = view.search_box() {
+            line_data(&mut self.search_box, &self.text_cache, display, text);
+        }
+        let (generation, highlights) = view.highlights();
+        if self.highlights.as_ref().map(|(previous, _)| *previous) != Some(generation) {
+            let data = highlights.iter()
+                .map(|(&key, spans)| {
+                    let items = spans.iter().map(|&span| (GroupId::default(), NameId(0), span));
+                    (key, BoxListData::from_iter(display, items))
+                })
+                .collect();
+            self.highlights = Some((generation, data));
+        }
+        let details = view.details();
+        self.details.resize_with(details.len(), || None);
+        for (line, text) in self.details.iter_mut().zip(details) {
+            line_data(line, &self.text_cache, display, text);
+        }
+        if let Some(ruler) = view.ruler() {
+            let stale = match &self.ruler_labels {
+                Some((labels, _)) => *labels != ruler.labels,
+                None => true,
+            };
+            if stale {
+                let strings = ruler.labels.iter().map(|(label, span)| (label.as_str(), *span));
+                let data = self.text_cache.string_data(display, strings);
+                self.ruler_labels = Some((ruler.labels, data));
+            }
+        }
+        let flame_labels = view.flame_labels();
+        if self.flame_labels.as_ref().map(|(previous, _)| previous) != Some(&flame_labels) {
+            let data = flame_labels.iter()
+                .map(|labels| self.text_cache.data(display, labels.iter().cloned()))
+                .collect();
+            self.flame_labels = Some((flame_labels, data));
+        }
+        let arrows = view.arrows();
+        let stale = match &self.arrows {
+            Some((previous, _)) => previous.as_slice() != arrows,
+            None => true,
+        };
+        if stale {
+            self.arrows = Some((arrows.to_vec(), ArrowData::new(display, arrows)));
+        }
+        let hops = view.hops();
+        if self.hops.as_ref().map(|(previous, _)| previous.as_slice()) != Some(hops) {
+            self.hops = Some((hops.to_vec(), ArrowData::new(display, hops)));
+        }
+
         let params = DrawParameters {
             depth: Depth {
                 test: DepthTest::Overwrite,
@@ -359,6 +653,18 @@ impl RenderState {
             .. Default::default()
         };
 
+        // Highlighted async tasks are lighter than the polls and sync spans on top of them.
+        let (r, g, b) = hsl_to_rgb(0.13, 0.95, 0.55);
+        let match_color = Color { r, g, b, a: 1.0 };
+        let (r, g, b) = hsl_to_rgb(0.13, 0.95, 0.8);
+        let match_back_color = Color { r, g, b, a: 1.0 };
+        let (r, g, b) = hsl_to_rgb(0.0, 0.75, 0.5);
+        let error_color = Color { r, g, b, a: 1.0 };
+        let (r, g, b) = hsl_to_rgb(0.0, 0.0, 0.6);
+        let cancelled_color = Color { r, g, b, a: 1.0 };
+        let (r, g, b) = hsl_to_rgb(0.1, 0.9, 0.55);
+        let delay_color = Color { r, g, b, a: 1.0 };
+
         for cmd in view.draw_commands() {
             match cmd {
                 DrawCommand::SimpleBox { color, region } => {
@@ -378,12 +684,78 @@ impl RenderState {
                         region);
                 },
                 DrawCommand::LabelList { key, region } => {
-                    self.label_lists[&key].draw(
-                        &self.text_cache,
-                        &params,
-                        target,
-                        region,
-                    );
+                    // Summary rows only have summary labels.
+                    for lists in &[&self.label_lists, &self.summary_lists] {
+                        if let Some(data) = lists.get(&key) {
+                            data.draw(&self.text_cache, &params, target, region);
+                        }
+                    }
+                },
+                DrawCommand::FlameLabels { depth, region } => {
+                    if let Some(data) = self.flame_labels.as_ref().and_then(|(_, data)| data.get(depth)) {
+                        data.draw(&self.text_cache, &params, target, region);
+                    }
+                },
+                DrawCommand::Arrows => {
+                    if let Some((_, data)) = &self.arrows {
+                        let (r, g, b) = hsl_to_rgb(0.08, 0.9, 0.5);
+                        let color = Color { r, g, b, a: 0.6 };
+                        let (r, g, b) = hsl_to_rgb(0.0, 0.68, 0.35);
+                        let selected_color = Color { r, g, b, a: 1.0 };
+                        data.draw(&self.shaders, &params, target, color, selected_color);
+                    }
+                },
+                DrawCommand::Hops => {
+                    if let Some((_, data)) = &self.hops {
+                        let (r, g, b) = hsl_to_rgb(0.6, 0.7, 0.55);
+                        let color = Color { r, g, b, a: 0.6 };
+                        let (r, g, b) = hsl_to_rgb(0.62, 0.8, 0.35);
+                        let selected_color = Color { r, g, b, a: 1.0 };
+                        data.draw(&self.shaders, &params, target, color, selected_color);
+                    }
+                },
+                DrawCommand::RulerLabels { region } => {
+                    if let Some((_, data)) = &self.ruler_labels {
+                        data.draw(&self.text_cache, &params, target, region);
+                    }
+                },
+                DrawCommand::StatusBar { region } => {
+                    if let Some((_, data)) = &self.status {
+                        data.draw(&self.text_cache, &params, target, region);
+                    }
+                },
+                DrawCommand::Outcomes { key, region } => {
+                    if let Some((errors, cancellations)) = self.outcome_lists.get(&key) {
+                        for (data, color) in &[(errors, error_color), (cancellations, cancelled_color)] {
+                            if data.len() > 0 {
+                                let range = SpanRange { begin: 0, end: data.len() };
+                                data.draw(&self.shaders, &params, target, range, &self.color_texture, *color, NameId(0xefffffff), *color, region);
+                            }
+                        }
+                    }
+                },
+                DrawCommand::Delays { key, region } => {
+                    if let Some(data) = self.delay_lists.get(&key) {
+                        let range = SpanRange { begin: 0, end: data.len() };
+                        data.draw(&self.shaders, &params, target, range, &self.color_texture, delay_color, NameId(0xefffffff), delay_color, region);
+                    }
+                },
+                DrawCommand::Highlight { key, region } => {
+                    if let Some(data) = self.highlights.as_ref().and_then(|(_, data)| data.get(&key)) {
+                        let color = if key.2 { match_back_color } else { match_color };
+                        let range = SpanRange { begin: 0, end: data.len() };
+                        data.draw(&self.shaders, &params, target, range, &self.color_texture, color, NameId(0xefffffff), color, region);
+                    }
+                },
+                DrawCommand::Details { line, region } => {
+                    if let Some(Some((_, data))) = self.details.get(line) {
+                        data.draw(&self.text_cache, &params, target, region);
+                    }
+                },
+                DrawCommand::SearchBox { region } => {
+                    if let Some((_, data)) = &self.search_box {
+                        data.draw(&self.text_cache, &params, target, region);
+                    }
                 },
             }
         }
@@ -0,0 +1,211 @@
+use cyclotron_backend::AsyncOutcome;
+use regex::Regex;
+use serde_json::Value;
+
+use crate::db::{Database, NameIdSet, Span, Task, TaskId, TaskIdSet};
+
+// What a search looks for: a regex on names, or `key == value` on metadata, where `key` can be a
+// dotted path into nested objects and `value` is JSON or, failing that, a bare string.
+enum Query {
+    Names(NameIdSet),
+    Metadata(Vec<String>, Value),
+    Failures,
+}
+
+// Stands in for the pattern of the failed or cancelled tasks filter, which isn't typed.
+const FAILURES: &str = "<failed or cancelled>";
+
+impl Query {
+    fn parse(db: &Database, pattern: &str) -> Result<Query, regex::Error> {
+        if let Some((key, value)) = pattern.split_once("==") {
+            let key = key.trim();
+            let is_key = !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.');
+            if is_key {
+                let value = value.trim();
+                let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+                return Ok(Query::Metadata(key.split('.').map(String::from).collect(), value));
+            }
+        }
+        Ok(Query::Names(db.names_matching_regex(Regex::new(&format!(".*{}.*", pattern))?)))
+    }
+
+    fn matches(&self, task: &Task) -> bool {
+        match self {
+            Query::Names(names) => names.contains(task.full_name),
+            Query::Metadata(path, value) => {
+                let found = path.iter().try_fold(&task.metadata, |metadata, key| metadata.get(key));
+                found == Some(value)
+            }
+            Query::Failures => matches!(task.outcome, Some(AsyncOutcome::Error(..)) | Some(AsyncOutcome::Cancelled)),
+        }
+    }
+}
+
+/// The spans matching a search in time order, so we can step through them.
+pub struct Search {
+    pub pattern: String,
+    pub tasks: TaskIdSet,
+    matches: Vec<(Span, TaskId)>,
+    current: Option<usize>,
+}
+
+impl Search {
+    pub fn new(db: &Database, pattern: &str) -> Result<Search, regex::Error> {
+        Ok(Search::from_query(db, pattern, Query::parse(db, pattern)?))
+    }
+
+    /// The async tasks that ended in an error or were cancelled.
+    pub fn failures(db: &Database) -> Search {
+        Search::from_query(db, FAILURES, Query::Failures)
+    }
+
+    pub fn is_failures(&self) -> bool {
+        self.pattern == FAILURES
+    }
+
+    fn from_query(db: &Database, pattern: &str, query: Query) -> Search {
+        let mut tasks = TaskIdSet::new();
+        let mut matches = vec![];
+        for task in &db.tasks {
+            if query.matches(task) {
+                tasks.insert(task.id);
+                matches.push((task.span, task.id));
+            }
+        }
+        matches.sort();
+        Search { pattern: pattern.to_string(), tasks, matches, current: None }
+    }
+
+    pub fn len(&self) -> usize {
+        self.matches.len()
+    }
+
+    /// Which match we're on, counting from one.
+    pub fn position(&self) -> Option<usize> {
+        self.current.map(|index| index + 1)
+    }
+
+    /// Step to the match after the current one, or the first one starting at or after `from` if
+    /// we haven't stepped yet, wrapping around at the end.
+    pub fn next(&mut self, from: u64) -> Option<(Span, TaskId)> {
+        if self.matches.is_empty() {
+            return None;
+        }
+        let index = match self.current {
+            Some(index) => (index + 1) % self.matches.len(),
+            None => {
+                let index = self.matches.iter().position(|(span, _)| span.begin >= from);
+                index.unwrap_or(0)
+            }
+        };
+        self.current = Some(index);
+        Some(self.matches[index])
+    }
+
+    /// Step to the match before the current one, or the last one starting before `from` if we
+    /// haven't stepped yet, wrapping around at the start.
+    pub fn previous(&mut self, from: u64) -> Option<(Span, TaskId)> {
+        if self.matches.is_empty() {
+            return None;
+        }
+        let index = match self.current {
+            Some(0) => self.matches.len() - 1,
+            Some(index) => index - 1,
+            None => {
+                let index = self.matches.iter().rposition(|(span, _)| span.begin < from);
+                index.unwrap_or(self.matches.len() - 1)
+            }
+        };
+        self.current = Some(index);
+        Some(self.matches[index])
+    }
+
+    /// What to show in the status bar.
+    pub fn describe(&self) -> String {
+        match self.position() {
+            Some(position) => format!("search {:?}: match {} of {}", self.pattern, position, self.len()),
+            None => format!("search {:?}: {} matches", self.pattern, self.len()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Search;
+    use crate::db::Database;
+
+    #[test]
+    fn test_next_previous() {
+        let path = "/tmp/glviewer_test_search.json";
+        std::fs::write(path, r#"[
+            {"ph": "X", "name": "outer", "ts": 0, "dur": 10, "pid": 1, "tid": 7},
+            {"ph": "X", "name": "inner", "ts": 0, "dur": 4, "pid": 1, "tid": 7},
+            {"ph": "X", "name": "call", "ts": 2, "dur": 1, "pid": 1, "tid": 7},
+            {"ph": "X", "name": "handler", "ts": 5, "dur": 3, "pid": 1, "tid": 8}
+        ]"#).unwrap();
+        let (db, _) = Database::load(path);
+        let name = |(_, task)| db.name(db.task(task).name);
+
+        let mut search = Search::new(&db, "er").unwrap();
+        assert_eq!(search.len(), 3);
+        assert_eq!(search.position(), None);
+
+        // We start from the first match at or after where we're looking, and wrap around.
+        assert_eq!(search.next(1000).map(name), Some("handler"));
+        assert_eq!(search.next(1000).map(name), Some("inner"));
+        assert_eq!(search.next(1000).map(name), Some("outer"));
+        assert_eq!(search.position(), Some(2));
+        assert_eq!(search.previous(1000).map(name), Some("inner"));
+
+        let mut search = Search::new(&db, "er").unwrap();
+        assert_eq!(search.previous(5000).map(name), Some("outer"));
+        assert_eq!(search.describe(), "search \"er\": match 2 of 3");
+
+        assert!(Search::new(&db, "(").is_err());
+        assert_eq!(Search::new(&db, "nothing").unwrap().next(0), None);
+    }
+
+    #[test]
+    fn test_metadata() {
+        let path = "/tmp/glviewer_test_search_metadata.json";
+        std::fs::write(path, r#"[
+            {"ph": "X", "name": "get", "ts": 0, "dur": 1, "pid": 1, "tid": 7, "args": {"path": "/foo", "attempt": 1}},
+            {"ph": "X", "name": "get", "ts": 2, "dur": 1, "pid": 1, "tid": 7, "args": {"path": "/bar", "req": {"id": 7}}},
+            {"ph": "X", "name": "put", "ts": 4, "dur": 1, "pid": 1, "tid": 7, "args": {"path": "/foo"}}
+        ]"#).unwrap();
+        let (db, _) = Database::load(path);
+        let count = |pattern| Search::new(&db, pattern).unwrap().len();
+
+        assert_eq!(count("path == /foo"), 2);
+        assert_eq!(count(r#"path=="/foo""#), 2);
+        assert_eq!(count("attempt == 1"), 1);
+        assert_eq!(count("attempt == \"1\""), 0);
+        assert_eq!(count("req.id == 7"), 1);
+        assert_eq!(count("missing == 7"), 0);
+        // Not a key, so it's a regex on names.
+        assert_eq!(count("get x == y"), 0);
+        assert_eq!(count("get"), 2);
+    }
+
+    #[test]
+    fn test_failures() {
+        let path = "/tmp/glviewer_test_search_failures.json";
+        std::fs::write(path, r#"[
+            {"ph": "b", "cat": "rpc", "name": "ok", "id": 1, "ts": 0, "pid": 1, "tid": 7},
+            {"ph": "e", "cat": "rpc", "name": "ok", "id": 1, "ts": 1, "pid": 1, "tid": 7},
+            {"ph": "b", "cat": "rpc", "name": "failed", "id": 2, "ts": 2, "pid": 1, "tid": 7},
+            {"ph": "e", "cat": "rpc", "name": "failed", "id": 2, "ts": 3, "pid": 1, "tid": 7, "args": {"outcome": {"error": "boom"}}},
+            {"ph": "b", "cat": "rpc", "name": "cancelled", "id": 3, "ts": 4, "pid": 1, "tid": 7},
+            {"ph": "e", "cat": "rpc", "name": "cancelled", "id": 3, "ts": 5, "pid": 1, "tid": 7, "args": {"outcome": "cancelled"}}
+        ]"#).unwrap();
+        let (db, _) = Database::load(path);
+
+        let mut search = Search::failures(&db);
+        assert!(search.is_failures());
+        assert!(!Search::new(&db, "failed").unwrap().is_failures());
+        assert_eq!(search.len(), 2);
+        let name = |(_, task)| db.name(db.task(task).name);
+        assert_eq!(search.next(0).map(name), Some("failed"));
+        assert_eq!(search.next(0).map(name), Some("cancelled"));
+    }
+}
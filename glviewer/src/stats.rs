@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::db::{Database, NameId, TaskId};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    Table,
+    Csv,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "table" => Ok(Format::Table),
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown format {:?}, expected table, csv or json", s)),
+        }
+    }
+}
+
+/// Summary of the spans with one name on one thread, in nanoseconds.
+#[derive(Debug, PartialEq)]
+pub struct Stats {
+    pub thread: String,
+    pub name: String,
+    pub count: usize,
+    pub total: u64,
+    pub mean: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+    // Time spent polled for async spans, none for threads, and the whole span for the others.
+    pub on_cpu: u64,
+    pub wakeups: usize,
+    // How long the spans waited to be polled after being woken, if they ever were.
+    pub latency: Option<Latency>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Latency {
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl Latency {
+    fn new(mut delays: Vec<u64>) -> Option<Latency> {
+        if delays.is_empty() {
+            return None;
+        }
+        delays.sort_unstable();
+        Some(Latency {
+            p50: percentile(&delays, 50),
+            p90: percentile(&delays, 90),
+            p99: percentile(&delays, 99),
+            max: *delays.last().unwrap(),
+        })
+    }
+}
+
+#[derive(Default)]
+struct Group {
+    durations: Vec<u64>,
+    on_cpu: u64,
+    delays: Vec<u64>,
+}
+
+// The nearest-rank percentile of sorted durations.
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    let rank = (sorted.len() * p).div_ceil(100);
+    sorted[rank.max(1) - 1]
+}
+
+/// Stats for each thread and span name, with threads in the order they started and the names on
+/// each thread by total time, longest first.
+pub fn compute(db: &Database) -> Vec<Stats> {
+    let mut roots: Vec<TaskId> = vec![];
+    let mut groups: HashMap<(TaskId, NameId), Group> = HashMap::new();
+    for task in &db.tasks {
+        let root = db.root(task.id);
+        if root == task.id {
+            roots.push(root);
+        }
+        // A thread's own span is mostly it waiting for work, so only its spans count as on-CPU.
+        let on_cpu = match &task.on_cpu {
+            Some(polls) => polls.iter().map(|span| span.end - span.begin).sum(),
+            None if task.parent.is_none() => 0,
+            None => task.span.end - task.span.begin,
+        };
+        let group = groups.entry((root, task.name)).or_default();
+        group.durations.push(task.span.end - task.span.begin);
+        group.on_cpu += on_cpu;
+        group.delays.extend(db.scheduling_delays(task.id).map(|delay| delay.span.end - delay.span.begin));
+    }
+
+    let mut by_root: HashMap<TaskId, Vec<(NameId, Group)>> = HashMap::new();
+    for ((root, name), group) in groups {
+        by_root.entry(root).or_default().push((name, group));
+    }
+
+    let mut res = vec![];
+    for root in roots {
+        let thread_name = db.name(db.task(root).full_name);
+        let mut thread: Vec<_> = by_root.remove(&root).unwrap_or_default().into_iter()
+            .map(|(name, mut group)| {
+                group.durations.sort_unstable();
+                let durations = group.durations;
+                let total: u64 = durations.iter().sum();
+                Stats {
+                    thread: thread_name.to_string(),
+                    name: db.name(name).to_string(),
+                    count: durations.len(),
+                    total,
+                    mean: total / durations.len() as u64,
+                    p50: percentile(&durations, 50),
+                    p90: percentile(&durations, 90),
+                    p99: percentile(&durations, 99),
+                    max: *durations.last().unwrap(),
+                    on_cpu: group.on_cpu,
+                    wakeups: group.delays.len(),
+                    latency: Latency::new(group.delays),
+                }
+            })
+            .collect();
+        thread.sort_by(|a, b| b.total.cmp(&a.total).then_with(|| a.name.cmp(&b.name)));
+        res.extend(thread);
+    }
+    res
+}
+
+const COLUMNS: [&str; 15] = [
+    "thread", "name", "count", "total", "mean", "p50", "p90", "p99", "max", "on_cpu",
+    "wakeups", "latency_p50", "latency_p90", "latency_p99", "latency_max",
+];
+
+impl Stats {
+    fn times(&self) -> [u64; 7] {
+        [self.total, self.mean, self.p50, self.p90, self.p99, self.max, self.on_cpu]
+    }
+
+    fn latencies(&self) -> Option<[u64; 4]> {
+        self.latency.as_ref().map(|l| [l.p50, l.p90, l.p99, l.max])
+    }
+}
+
+// Quote a CSV field if it needs it.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Write stats out as an aligned table with readable durations, or as CSV or JSON with durations
+/// in nanoseconds.
+pub fn write(stats: &[Stats], format: Format, mut out: impl Write) -> io::Result<()> {
+    match format {
+        Format::Table => {
+            let rows: Vec<Vec<String>> = stats.iter().map(|s| {
+                let mut row = vec![s.thread.clone(), s.name.clone(), s.count.to_string()];
+                row.extend(s.times().iter().map(|&t| format!("{:?}", Duration::from_nanos(t))));
+                row.push(s.wakeups.to_string());
+                match s.latencies() {
+                    Some(latencies) => row.extend(latencies.iter().map(|&t| format!("{:?}", Duration::from_nanos(t)))),
+                    None => row.extend(vec!["-".to_string(); 4]),
+                }
+                row
+            }).collect();
+            let mut widths: Vec<usize> = COLUMNS.iter().map(|c| c.len()).collect();
+            for row in &rows {
+                for (width, cell) in widths.iter_mut().zip(row) {
+                    *width = std::cmp::max(*width, cell.len());
+                }
+            }
+            let header: Vec<String> = COLUMNS.iter().map(|c| c.to_string()).collect();
+            for row in Some(&header).into_iter().chain(&rows) {
+                let mut line = String::new();
+                for (i, (cell, width)) in row.iter().zip(&widths).enumerate() {
+                    // Text is left-aligned and numbers right-aligned.
+                    if i < 2 {
+                        line.push_str(&format!("{:<1$}  ", cell, width));
+                    } else {
+                        line.push_str(&format!("{:>1$}  ", cell, width));
+                    }
+                }
+                writeln!(out, "{}", line.trim_end())?;
+            }
+        }
+        Format::Csv => {
+            writeln!(out, "{}", COLUMNS.join(","))?;
+            for s in stats {
+                let times: Vec<String> = s.times().iter().map(|t| t.to_string()).collect();
+                // Spans that were never woken have no latencies.
+                let latencies = match s.latencies() {
+                    Some(latencies) => latencies.iter().map(|t| t.to_string()).collect(),
+                    None => vec![String::new(); 4],
+                };
+                writeln!(out, "{},{},{},{},{},{}",
+                    csv_field(&s.thread), csv_field(&s.name), s.count, times.join(","), s.wakeups, latencies.join(","))?;
+            }
+        }
+        Format::Json => {
+            let stats: Vec<_> = stats.iter().map(|s| json!({
+                "thread": s.thread,
+                "name": s.name,
+                "count": s.count,
+                "total_ns": s.total,
+                "mean_ns": s.mean,
+                "p50_ns": s.p50,
+                "p90_ns": s.p90,
+                "p99_ns": s.p99,
+                "max_ns": s.max,
+                "on_cpu_ns": s.on_cpu,
+                "wakeups": s.wakeups,
+                "latency_p50_ns": s.latency.as_ref().map(|l| l.p50),
+                "latency_p90_ns": s.latency.as_ref().map(|l| l.p90),
+                "latency_p99_ns": s.latency.as_ref().map(|l| l.p99),
+                "latency_max_ns": s.latency.as_ref().map(|l| l.max),
+            })).collect();
+            serde_json::to_writer_pretty(&mut out, &stats)?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compute, write, Format};
+
+    #[test]
+    fn test_stats() {
+        let db = crate::db::tests::load_fixture();
+
+        let stats = compute(&db);
+        let names: Vec<_> = stats.iter().map(|s| (s.thread.as_str(), s.name.as_str())).collect();
+        assert_eq!(names, vec![("thread", "thread"), ("thread", "RemoteAdd"), ("thread", "sync")]);
+        // The thread's idle time isn't on-CPU.
+        assert_eq!((stats[0].total, stats[0].on_cpu), (9_000_000, 0));
+        // Polled for 1ms at a time, twice, over 7ms.
+        let remote = &stats[1];
+        assert_eq!((remote.count, remote.total, remote.max, remote.on_cpu), (1, 7_000_000, 7_000_000, 2_000_000));
+        // Woken at 5ms and polled at 7ms.
+        assert_eq!(remote.wakeups, 1);
+        assert_eq!(remote.latency.as_ref().map(|l| l.max), Some(2_000_000));
+        assert_eq!(stats[2].latency, None);
+
+        let mut csv = vec![];
+        write(&stats, Format::Csv, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().next(), Some("thread,name,count,total,mean,p50,p90,p99,max,on_cpu,wakeups,latency_p50,latency_p90,latency_p99,latency_max"));
+        assert_eq!(csv.lines().nth(2), Some("thread,RemoteAdd,1,7000000,7000000,7000000,7000000,7000000,7000000,2000000,1,2000000,2000000,2000000,2000000"));
+        assert_eq!(csv.lines().nth(3), Some("thread,sync,1,2000000,2000000,2000000,2000000,2000000,2000000,2000000,0,,,,"));
+
+        let mut json = vec![];
+        write(&stats, Format::Json, &mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json[1]["on_cpu_ns"], 2_000_000);
+        assert_eq!(json[1]["latency_p50_ns"], 2_000_000);
+        assert!(json[2]["latency_p50_ns"].is_null());
+
+        let mut table = vec![];
+        write(&stats, Format::Table, &mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        assert!(table.lines().nth(2).unwrap().starts_with("thread  RemoteAdd      1    7ms   7ms"), "{}", table);
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let sorted: Vec<u64> = (1..=200).collect();
+        assert_eq!(super::percentile(&sorted, 50), 100);
+        assert_eq!(super::percentile(&sorted, 99), 198);
+        assert_eq!(super::percentile(&[7], 90), 7);
+    }
+}
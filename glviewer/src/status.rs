@@ -0,0 +1,151 @@
+use std::time::Duration;
+
+use cyclotron_backend::AsyncOutcome;
+use serde_json::Value;
+
+use crate::db::{Database, TaskId};
+use crate::view::{View, SelectionInfo};
+
+// Separates the parts of the status line.
+const SEPARATOR: &str = "  |  ";
+
+fn names(db: &Database, tasks: impl Iterator<Item=TaskId>) -> Vec<&str> {
+    tasks.map(|task| db.name(db.task(task).full_name)).collect()
+}
+
+/// Describe what's under the cursor, with times relative to `origin`.
+pub fn describe_selection(db: &Database, selection: SelectionInfo, origin: u64, span_time: u64, show_wakes: bool) -> String {
+    match selection {
+        SelectionInfo::Span { span, task, .. } => {
+            let mut parts = vec![
+                db.name(db.task(task).full_name).to_string(),
+                format!("start {:?}", Duration::from_nanos(span.begin.saturating_sub(origin))),
+                format!("duration {:?}", Duration::from_nanos(span.end - span.begin)),
+            ];
+            if let Some(parent) = db.task(task).parent {
+                parts.push(format!("parent {}", db.name(db.task(parent).full_name)));
+            }
+            match &db.task(task).outcome {
+                Some(AsyncOutcome::Error(e)) => parts.push(format!("error: {}", e)),
+                Some(AsyncOutcome::Cancelled) => parts.push("cancelled".to_string()),
+                _ => (),
+            }
+
+            if show_wakes {
+                let woken_by = names(db, db.parks(task).iter().map(|park| park.waking));
+                if !woken_by.is_empty() {
+                    parts.push(format!("woken by {}", woken_by.join(", ")));
+                }
+                let wakes = names(db, db.wakes(task).iter().map(|wake| wake.parked));
+                if !wakes.is_empty() {
+                    parts.push(format!("wakes {}", wakes.join(", ")));
+                }
+            }
+
+            let migrations = db.migrations(task);
+            if migrations.len() > 1 {
+                let threads: Vec<_> = migrations.iter()
+                    .map(|m| format!("{} ({:?})", db.name(db.task(m.thread).name), Duration::from_nanos(m.nanos.saturating_sub(origin))))
+                    .collect();
+                parts.push(format!("polled on {}", threads.join(" -> ")));
+            }
+            parts.join(SEPARATOR)
+        }
+        SelectionInfo::Frame { name, total, self_time } => {
+            format!("{}{}total {:?}{}self {:?}",
+                db.name(name),
+                SEPARATOR,
+                Duration::from_nanos(total),
+                SEPARATOR,
+                Duration::from_nanos(self_time))
+        }
+        SelectionInfo::ProfileName { name, time } => {
+            format!("{}{}{:?} ({:.2}%)",
+                db.name(name),
+                SEPARATOR,
+                Duration::from_nanos(time),
+                time as f32 / span_time as f32 * 100.0)
+        }
+    }
+}
+
+// Most lines of metadata to show in the details panel.
+const MAX_DETAILS: usize = 20;
+
+// Flatten metadata into `key: value` lines, with dotted keys for nested objects like searches use.
+fn flatten(prefix: &str, value: &Value, lines: &mut Vec<String>) {
+    match value {
+        Value::Object(obj) => {
+            for (key, value) in obj {
+                let key = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                flatten(&key, value, lines);
+            }
+        }
+        Value::String(s) => lines.push(format!("{}: {}", prefix, s)),
+        value => lines.push(format!("{}: {}", prefix, value)),
+    }
+}
+
+/// Lines for the details panel: the task's name followed by its metadata, or nothing if it
+/// doesn't have any.
+pub fn details(db: &Database, task: TaskId) -> Vec<String> {
+    let task = db.task(task);
+    if task.metadata.is_null() {
+        return vec![];
+    }
+    let mut lines = vec![];
+    let prefix = if task.metadata.is_object() { "" } else { "metadata" };
+    flatten(prefix, &task.metadata, &mut lines);
+    if lines.len() > MAX_DETAILS {
+        let more = lines.len() - MAX_DETAILS;
+        lines.truncate(MAX_DETAILS);
+        lines.push(format!("... {} more", more));
+    }
+    lines.insert(0, db.name(task.full_name).to_string());
+    lines
+}
+
+/// The text for the status bar: what's under the cursor, the window we're looking at, and
+/// `message`, which is whatever the last command had to say.
+pub fn status_line(db: &Database, view: &View, show_wakes: bool, message: &str) -> String {
+    let origin = view.origin();
+    let span = view.span();
+    let mut parts = vec![];
+    if let Some(selection) = view.selection() {
+        parts.push(describe_selection(db, selection, origin, view.span_time(), show_wakes));
+    }
+    parts.push(format!("window {:?} to {:?}",
+        Duration::from_nanos(span.begin.saturating_sub(origin)),
+        Duration::from_nanos(span.end.saturating_sub(origin))));
+    if !message.is_empty() {
+        parts.push(message.to_string());
+    }
+    parts.join(SEPARATOR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{describe_selection, details};
+    use crate::db::TaskId;
+    use crate::view::SelectionInfo;
+
+    #[test]
+    fn test_describe_selection() {
+        let db = crate::db::tests::load_fixture();
+
+        let describe = |task: TaskId, show_wakes| {
+            let task = db.task(task);
+            let selection = SelectionInfo::Span { task: task.id, name: task.name, span: task.span };
+            describe_selection(&db, selection, 1_000_000, 10_000_000, show_wakes)
+        };
+        let parked = describe(TaskId(1), true);
+        assert!(parked.starts_with("RemoteAdd(/foo)  |  start "), "{}", parked);
+        assert!(parked.contains("parent thread"), "{}", parked);
+        assert!(parked.contains("woken by sync"), "{}", parked);
+        assert!(!describe(TaskId(1), false).contains("woken by"));
+        assert!(describe(TaskId(2), true).contains("wakes RemoteAdd(/foo)"));
+
+        assert_eq!(details(&db, TaskId(1)), vec!["RemoteAdd(/foo)", "path: /foo", "request.attempt: 2"]);
+        assert!(details(&db, TaskId(2)).is_empty());
+    }
+}
@@ -34,10 +34,19 @@ struct ScaledGlyph {
 
 pub struct TextCache {
     labels: HashMap<NameId, Vec<ScaledGlyph>>,
+    // Glyphs for typesetting arbitrary strings a character at a time, along with how far to advance
+    // after each one.
+    chars: HashMap<char, (Option<ScaledGlyph>, f32)>,
     texture: Texture2d,
     program: Program,
 }
 
+// Characters we can typeset in strings that aren't names, like the time ruler's labels and the
+// status bar: printable ASCII, plus the µ in microseconds.
+fn string_chars() -> impl Iterator<Item=char> {
+    (' '..='~').chain(Some('µ'))
+}
+
 // Texture size (before scale factor)
 const CACHE_SIZE: f64 = 512.;
 
@@ -101,6 +110,16 @@ impl TextCache {
             glyphs_by_name.insert(name_id, glyphs);
         }
 
+        // Then do the same for each character we can put in a string, starting at the label's
+        // padding so they line up with the names.
+        let mut glyphs_by_char = HashMap::new();
+        for c in string_chars() {
+            let glyph = font.glyph(c).scaled(scale).positioned(rusttype::point(LABEL_LEFT_PADDING, v_metrics.ascent));
+            let advance = glyph.unpositioned().h_metrics().advance_width;
+            cache.queue_glyph(0, glyph.clone());
+            glyphs_by_char.insert(c, (glyph, advance));
+        }
+
         // Build the texture of all unique glyphs within our input strings.
         cache.cache_queued(|rect, data| {
             texture.main_level().write(
@@ -140,6 +159,16 @@ impl TextCache {
             }
             rectangles_by_name_id.insert(name_id, rectangles);
         }
+        let mut rectangles_by_char = HashMap::with_capacity(glyphs_by_char.len());
+        for (c, (glyph, advance)) in glyphs_by_char {
+            let rectangle = cache.rect_for(0, &glyph)
+                .unwrap_or_else(|_| panic!("Failed to find {:?}", glyph));
+            if let Some((_, screen_rect)) = rectangle {
+                min_y = std::cmp::min(min_y, screen_rect.min.y);
+                max_y = std::cmp::max(max_y, screen_rect.max.y);
+            }
+            rectangles_by_char.insert(c, (rectangle, advance));
+        }
         assert!(min_y < max_y);
         // TODO: This isn't typographically correct. We should be using `VMetrics` somehow.
         let scale = LABEL_LINE_HEIGHT / (max_y - min_y) as f32;
@@ -156,68 +185,104 @@ impl TextCache {
             }
             labels.insert(name_id, scaled_glyphs);
         }
+        let mut chars = HashMap::with_capacity(rectangles_by_char.len());
+        for (c, (rectangle, advance)) in rectangles_by_char {
+            let glyph = rectangle.map(|(uv_rect, screen_rect)| {
+                let min = Vector { x: screen_rect.min.x as f32, y: screen_rect.min.y as f32 } * scale;
+                let max = Vector { x: screen_rect.max.x as f32, y: screen_rect.max.y as f32 } * scale;
+                ScaledGlyph { min, max, uv_rect }
+            });
+            chars.insert(c, (glyph, advance * scale));
+        }
 
-        Self { labels, texture, program: Self::program(display) }
+        Self { labels, chars, texture, program: Self::program(display) }
+    }
+
+    /// Like `data`, but for arbitrary strings rather than names.  Characters we don't have glyphs
+    /// for are skipped.
+    pub fn string_data<'a>(&self, display: &Display, strings: impl Iterator<Item=(&'a str, Span)>) -> LabelListData {
+        let mut glyphs = vec![];
+        for (string, span) in strings {
+            let mut caret = 0.;
+            for c in string.chars() {
+                if let Some((glyph, advance)) = self.chars.get(&c) {
+                    if let Some(glyph) = glyph {
+                        let offset = Vector { x: caret, y: 0. };
+                        glyphs.push((
+                            ScaledGlyph { min: glyph.min + offset, max: glyph.max + offset, uv_rect: glyph.uv_rect },
+                            span,
+                        ));
+                    }
+                    caret += advance;
+                }
+            }
+        }
+        Self::glyph_data(display, glyphs.iter().map(|(glyph, span)| (glyph, *span)))
     }
 
     pub fn data(&self, display: &Display, labels: impl Iterator<Item=(NameId, Span)>) -> LabelListData {
+        let glyphs = labels.flat_map(|(name_id, span)| {
+            self.labels.get(&name_id).unwrap().iter().map(move |glyph| (glyph, span))
+        });
+        Self::glyph_data(display, glyphs)
+    }
+
+    fn glyph_data<'a>(display: &Display, glyphs: impl Iterator<Item=(&'a ScaledGlyph, Span)>) -> LabelListData {
         let mut vertices = vec![];
         let mut triangles = vec![];
 
-        for (name_id, span) in labels {
-            for ScaledGlyph { min, max, uv_rect } in self.labels.get(&name_id).unwrap() {
-                let s = vertices.len() as u32;
-                let task_begin = (span.begin as f32) / 1e9;
-                let task_end = (span.end as f32) / 1e9;
-
-                let tex_base = [uv_rect.min.x, uv_rect.min.y];
-                let tex_dimensions = [uv_rect.max.x - uv_rect.min.x, uv_rect.max.y - uv_rect.min.y];
-
-                vertices.extend(&[
-                    TextVertex {
-                        glyph: [min.x, min.y],
-
-                        tex_pos: [0., 0.],
-                        tex_base,
-                        tex_dimensions,
-
-                        task_begin,
-                        task_end,
-                    },
-                    TextVertex {
-                        glyph: [max.x, min.y],
-
-                        tex_pos: [1., 0.],
-                        tex_base,
-                        tex_dimensions,
-
-                        task_begin,
-                        task_end,
-                    },
-                    TextVertex {
-                        glyph: [min.x, max.y],
-
-                        tex_pos: [0., 1.],
-                        tex_base,
-                        tex_dimensions,
-
-                        task_begin,
-                        task_end,
-                    },
-                    TextVertex {
-                        glyph: [max.x, max.y],
-
-                        tex_pos: [1., 1.],
-                        tex_base,
-                        tex_dimensions,
-
-                        task_begin,
-                        task_end,
-                    },
-                ]);
-
-                triangles.extend(&[s, s+1, s+2, s+1, s+2, s+3]);
-            }
+        for (ScaledGlyph { min, max, uv_rect }, span) in glyphs {
+            let s = vertices.len() as u32;
+            let task_begin = (span.begin as f32) / 1e9;
+            let task_end = (span.end as f32) / 1e9;
+
+            let tex_base = [uv_rect.min.x, uv_rect.min.y];
+            let tex_dimensions = [uv_rect.max.x - uv_rect.min.x, uv_rect.max.y - uv_rect.min.y];
+
+            vertices.extend(&[
+                TextVertex {
+                    glyph: [min.x, min.y],
+
+                    tex_pos: [0., 0.],
+                    tex_base,
+                    tex_dimensions,
+
+                    task_begin,
+                    task_end,
+                },
+                TextVertex {
+                    glyph: [max.x, min.y],
+
+                    tex_pos: [1., 0.],
+                    tex_base,
+                    tex_dimensions,
+
+                    task_begin,
+                    task_end,
+                },
+                TextVertex {
+                    glyph: [min.x, max.y],
+
+                    tex_pos: [0., 1.],
+                    tex_base,
+                    tex_dimensions,
+
+                    task_begin,
+                    task_end,
+                },
+                TextVertex {
+                    glyph: [max.x, max.y],
+
+                    tex_pos: [1., 1.],
+                    tex_base,
+                    tex_dimensions,
+
+                    task_begin,
+                    task_end,
+                },
+            ]);
+
+            triangles.extend(&[s, s+1, s+2, s+1, s+2, s+3]);
         }
 
         let vertex_buffer = VertexBuffer::new(display, &vertices).unwrap();
@@ -1,5 +1,6 @@
 use std::collections::{HashSet, HashMap};
-use crate::db::{Span, NameId, NameIdSet, TaskId};
+use crate::db::{Span, NameId, TaskId, TaskIdSet};
+use crate::flame::{self, Frame};
 use crate::layout::{Layout, ThreadId, RowId, BoxListKey, SpanRange, LabelListKey};
 use crate::render::{DrawCommand, Color, Region, SimpleRegion};
 use crate::util::hsl_to_rgb;
@@ -11,7 +12,25 @@ pub struct View {
     derived: Derived,
     limits: Span,
     span: Span,
+    tasks: Option<TaskIdSet>,
     filter: HashSet<(ThreadId, RowId)>,
+    absolute_time: bool,
+    show_delays: bool,
+    arrow_mode: ArrowMode,
+    // The call path at the top of the flame graph, or nothing to show every thread.
+    flame_root: Vec<NameId>,
+    // Tasks to highlight, with where their boxes are, and a count of changes to them.
+    highlight: Option<TaskIdSet>,
+    // If set, only the parts of each highlighted task's boxes within its spans here are highlighted.
+    highlight_clip: Option<HashMap<TaskId, Vec<Span>>>,
+    // Only highlight boxes in the fore subrows, where polls are drawn.
+    highlight_fore_only: bool,
+    highlights: HashMap<BoxListKey, Vec<Span>>,
+    highlight_generation: u64,
+    // What's been typed into the search box if it's open.
+    search_box: Option<String>,
+    // Details about the selected span.
+    details: Vec<String>,
 }
 
 fn bounded(a: u64, b: u64, c: u64) -> u64 {
@@ -46,10 +65,132 @@ fn minmaxf(a: f64, b: f64) -> (f64, f64) {
 
 const MIN_WIDTH: f64 = 1e5;
 
+// Height of the time ruler at the top of the trace view, as a fraction of the window. The labels
+// go in the top part and the ticks underneath them.
+const RULER_HEIGHT: f32 = 0.04;
+const RULER_LABEL_HEIGHT: f32 = 0.6 * RULER_HEIGHT;
+
+// Aim for about this many labelled ticks across the window.
+const RULER_TICKS: u64 = 8;
+
+// Width of a tick mark, as a fraction of the window.
+const TICK_WIDTH: f32 = 0.001;
+
+// Height of the status bar along the bottom of the window.
+const STATUS_HEIGHT: f32 = 0.03;
+
+// The details panel for the selected span sits under the ruler on the right.
+const DETAILS_WIDTH: f32 = 0.35;
+const DETAILS_LINE_HEIGHT: f32 = 0.025;
+
+// The search box sits on top of the status bar on the left.
+const SEARCH_BOX_WIDTH: f32 = 0.4;
+const SEARCH_BOX_TOP: f32 = 1.0 - 2.0 * STATUS_HEIGHT;
+
+// Tallest a level of the flame graph gets; they shrink to fit deep graphs in the window.
+const FLAME_ROW_HEIGHT: f32 = 0.03;
+
+// Flame graph labels are laid out across one logical second, the graph's width.
+const FLAME_LOGICAL_WIDTH: f64 = 1e9;
+
+// Where a row of height one starting at `base` goes vertically, out of `total` rows, leaving room
+// for the ruler and the status bar.
+fn row_extent(base: f32, total: f32) -> (f32, f32) {
+    let height = (1.0 - RULER_HEIGHT - STATUS_HEIGHT) / total;
+    (RULER_HEIGHT + base * height, RULER_HEIGHT + (base + 1.0) * height)
+}
+
+// Spacing between labelled ticks and between all ticks, picked from 1, 2 and 5 times a power of
+// ten so we end up with about `RULER_TICKS` labels.
+fn tick_spacing(span_time: u64) -> (u64, u64) {
+    let target = std::cmp::max(span_time / RULER_TICKS, 1);
+    let mut magnitude = 1;
+    while magnitude * 10 <= target {
+        magnitude *= 10;
+    }
+    for &(mantissa, minor) in &[(1, 5), (2, 4), (5, 5), (10, 5)] {
+        if mantissa * magnitude >= target {
+            let major = mantissa * magnitude;
+            return (major, std::cmp::max(major / minor, 1));
+        }
+    }
+    unreachable!()
+}
+
+// Unit for labelling a window of `span_time`.
+fn time_unit(span_time: u64) -> (u64, &'static str) {
+    if span_time < 10_000 {
+        (1, "ns")
+    } else if span_time < 10_000_000 {
+        (1_000, "µs")
+    } else if span_time < 10_000_000_000 {
+        (1_000_000, "ms")
+    } else {
+        (1_000_000_000, "s")
+    }
+}
+
+fn format_time(nanos: u64, step: u64, (unit, suffix): (u64, &str)) -> String {
+    // Use as many decimal places as it takes to tell ticks apart.
+    let mut decimals = 0;
+    let mut scaled_step = step;
+    while scaled_step % unit != 0 && decimals < 9 {
+        scaled_step *= 10;
+        decimals += 1;
+    }
+    format!("{:.*} {}", decimals, nanos as f64 / unit as f64, suffix)
+}
+
+/// Tick marks for the ruler across `span`, labelled relative to `origin`.
+pub struct Ruler {
+    pub ticks: Vec<(u64, bool)>,
+    pub labels: Vec<(String, Span)>,
+}
+
+fn ruler(span: Span, origin: u64) -> Ruler {
+    let span_time = span.end - span.begin;
+    let (major, minor) = tick_spacing(span_time);
+    let unit = time_unit(span_time);
+
+    let mut ticks = Vec::new();
+    let mut labels = Vec::new();
+    // Line ticks up with the origin rather than with the edge of the window.
+    let offset = span.begin.saturating_sub(origin);
+    let mut tick = origin + offset.div_ceil(minor) * minor;
+    while tick <= span.end {
+        let is_major = (tick - origin) % major == 0;
+        ticks.push((tick, is_major));
+        if is_major {
+            let label = format_time(tick - origin, major, unit);
+            labels.push((label, Span { begin: tick, end: tick + major }));
+        }
+        tick += minor;
+    }
+    Ruler { ticks, labels }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Mode {
     Trace,
     Profile,
+    Flame,
+}
+
+/// Which wakeups and thread hops to draw arrows for.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ArrowMode {
+    Selected,
+    Visible,
+    Off,
+}
+
+/// An arrow from the waking span to the parked span, or from the thread a task was polled on to the
+/// next one, in window coordinates.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Arrow {
+    pub from: (f32, f32),
+    pub to: (f32, f32),
+    pub selected: bool,
 }
 
 #[derive(Eq, PartialEq, Copy, Clone)]
@@ -62,7 +203,12 @@ pub enum SelectionInfo {
     ProfileName {
         name: NameId,
         time: u64,
-    }
+    },
+    Frame {
+        name: NameId,
+        total: u64,
+        self_time: u64,
+    },
 }
 
 #[derive(Copy, Clone)]
@@ -85,27 +231,205 @@ struct InternalProfileSelectionInfo {
 
 impl View {
     pub fn new(layout: &Layout) -> View {
-        let limits = layout.span_discounting_threads();
+        let limits = limits(layout);
         let cursor = (0.0, 0.0);
         let mode = Mode::Trace;
         let filter = compute_filtered_row_set(None, layout);
+        let arrow_mode = ArrowMode::Selected;
         View {
             cursor,
             mode,
             cursor_down: None,
-            derived: derived(&filter, cursor, limits, mode, layout),
+            derived: derived(&filter, cursor, limits, mode, arrow_mode, &[], layout),
             limits,
             span: limits,
+            tasks: None,
             filter,
+            absolute_time: false,
+            show_delays: true,
+            arrow_mode,
+            flame_root: Vec::new(),
+            highlight: None,
+            highlight_clip: None,
+            highlight_fore_only: false,
+            highlights: HashMap::new(),
+            highlight_generation: 0,
+            search_box: None,
+            details: Vec::new(),
         }
     }
 
+    /// Cycle between drawing wakeup and thread hop arrows for the selected span, for everything
+    /// visible, and not at all.
+    pub fn cycle_arrow_mode(&mut self, layout: &Layout) -> ArrowMode {
+        self.arrow_mode = match self.arrow_mode {
+            ArrowMode::Selected => ArrowMode::Visible,
+            ArrowMode::Visible => ArrowMode::Off,
+            ArrowMode::Off => ArrowMode::Selected,
+        };
+        self.invalidate(layout);
+        self.arrow_mode
+    }
+
+    pub fn arrows(&self) -> &[Arrow] {
+        match &self.derived.mode {
+            DerivedMode::Trace { arrows, .. } => arrows,
+            DerivedMode::Profile { .. } | DerivedMode::Flame { .. } => &[],
+        }
+    }
+
+    pub fn hops(&self) -> &[Arrow] {
+        match &self.derived.mode {
+            DerivedMode::Trace { hops, .. } => hops,
+            DerivedMode::Profile { .. } | DerivedMode::Flame { .. } => &[],
+        }
+    }
+
+    /// Label the ruler with time since the tracing epoch, rather than since the start of the trace.
+    pub fn set_absolute_time(&mut self, absolute_time: bool) {
+        self.absolute_time = absolute_time;
+    }
+
+    pub fn absolute_time(&self) -> bool {
+        self.absolute_time
+    }
+
+    /// Shade the time async tasks spent waiting to be polled after being woken.
+    pub fn set_show_delays(&mut self, show_delays: bool) {
+        self.show_delays = show_delays;
+    }
+
+    pub fn show_delays(&self) -> bool {
+        self.show_delays
+    }
+
+    /// Highlight the spans of these tasks, as for a search.
+    pub fn set_highlight(&mut self, highlight: Option<TaskIdSet>, layout: &Layout) {
+        self.highlight = highlight;
+        self.highlight_clip = None;
+        self.highlight_fore_only = false;
+        self.highlights = compute_highlights(self.highlight.as_ref(), None, false, layout);
+        self.highlight_generation += 1;
+    }
+
+    /// Highlight just these parts of tasks, as for a critical path, or only of their polls and
+    /// sync spans if `fore_only`.
+    pub fn set_highlight_spans(&mut self, spans: impl Iterator<Item=(TaskId, Span)>, fore_only: bool, layout: &Layout) {
+        let mut tasks = TaskIdSet::new();
+        let mut clip: HashMap<TaskId, Vec<Span>> = HashMap::new();
+        for (task, span) in spans {
+            tasks.insert(task);
+            clip.entry(task).or_default().push(span);
+        }
+        self.highlight = Some(tasks);
+        self.highlight_clip = Some(clip);
+        self.highlight_fore_only = fore_only;
+        self.highlights = compute_highlights(self.highlight.as_ref(), self.highlight_clip.as_ref(), fore_only, layout);
+        self.highlight_generation += 1;
+    }
+
+    /// The boxes to highlight, along with a count that changes whenever they do.
+    pub fn highlights(&self) -> (u64, &HashMap<BoxListKey, Vec<Span>>) {
+        (self.highlight_generation, &self.highlights)
+    }
+
+    /// Show these lines in the details panel, or hide it if there aren't any.
+    pub fn set_details(&mut self, details: Vec<String>) {
+        self.details = details;
+    }
+
+    pub fn details(&self) -> &[String] {
+        &self.details
+    }
+
+    /// Show the search box with this text in it, or hide it.
+    pub fn set_search_box(&mut self, text: Option<String>) {
+        self.search_box = text;
+    }
+
+    pub fn search_box(&self) -> Option<&str> {
+        self.search_box.as_deref()
+    }
+
+    /// Where times are measured from: the start of the trace, or the tracing epoch.
+    pub fn origin(&self) -> u64 {
+        if self.absolute_time { 0 } else { self.limits.begin }
+    }
+
+    pub fn ruler(&self) -> Option<Ruler> {
+        if self.mode != Mode::Trace {
+            return None;
+        }
+        Some(ruler(self.span, self.origin()))
+    }
+
+    /// Catch up with a layout that's grown, as when following a trace.  If we were looking at the
+    /// end of the trace, we keep doing so.
+    pub fn update(&mut self, layout: &Layout) {
+        let old_limits = self.limits;
+        self.limits = limits(layout);
+        if self.span.end == old_limits.end && self.limits.end > old_limits.end {
+            let width = self.span.end - self.span.begin;
+            self.span.end = self.limits.end;
+            self.span.begin = std::cmp::max(self.limits.begin, self.limits.end.saturating_sub(width));
+            if self.span.begin == old_limits.begin {
+                // We were looking at everything, so keep doing that.
+                self.span.begin = self.limits.begin;
+            }
+        }
+        self.filter = compute_filtered_row_set(self.tasks.as_ref(), layout);
+        if self.highlight.is_some() {
+            self.highlights = compute_highlights(self.highlight.as_ref(), self.highlight_clip.as_ref(), self.highlight_fore_only, layout);
+            self.highlight_generation += 1;
+        }
+        self.invalidate(layout);
+    }
+
+    /// Cycle between the trace, the flat profile and the flame graph.
     pub fn toggle_mode(&mut self, layout: &Layout) {
         self.mode = match self.mode {
             Mode::Trace => Mode::Profile,
-            Mode::Profile => Mode::Trace,
+            Mode::Profile => Mode::Flame,
+            Mode::Flame => Mode::Trace,
+        };
+        self.invalidate(layout);
+    }
+
+    /// Put the flame graph frame under the cursor at the top of the graph, returning whether
+    /// there was one.
+    pub fn zoom_flame(&mut self, layout: &Layout) -> bool {
+        let path = match &self.derived.mode {
+            DerivedMode::Flame { frames, selection: Some(selection) } => frames[*selection].path.clone(),
+            _ => return false,
         };
+        self.flame_root = path;
         self.invalidate(layout);
+        true
+    }
+
+    /// Go back to the frame above the top of the flame graph, returning whether we'd zoomed in.
+    pub fn unzoom_flame(&mut self, layout: &Layout) -> bool {
+        if self.mode != Mode::Flame || self.flame_root.pop().is_none() {
+            return false;
+        }
+        self.invalidate(layout);
+        true
+    }
+
+    /// The labels for each level of the flame graph.
+    pub fn flame_labels(&self) -> Vec<Vec<(NameId, Span)>> {
+        let mut res: Vec<Vec<_>> = vec![];
+        if let DerivedMode::Flame { frames, .. } = &self.derived.mode {
+            for frame in frames {
+                if res.len() <= frame.depth {
+                    res.resize_with(frame.depth + 1, Vec::new);
+                }
+                let begin = (frame.left * FLAME_LOGICAL_WIDTH) as u64;
+                let end = (frame.right * FLAME_LOGICAL_WIDTH) as u64;
+                res[frame.depth].push((frame.name(), Span { begin, end }));
+            }
+        }
+        res
     }
 
     pub fn begin_drag(&mut self) {
@@ -116,9 +440,10 @@ impl View {
         self.cursor_down = None;
     }
 
-    pub fn set_filter(&mut self, filter: Option<NameIdSet>, layout: &Layout) {
+    /// Only show rows with one of these tasks in them.
+    pub fn set_filter(&mut self, filter: Option<TaskIdSet>, layout: &Layout) {
         self.filter = compute_filtered_row_set(filter.as_ref(), &layout);
-        println!("visible rows {:?}", self.filter.len());
+        self.tasks = filter;
         self.invalidate(layout);
     }
 
@@ -157,19 +482,31 @@ impl View {
                     time: selection.time
                 })
             }
+            DerivedMode::Flame { frames, selection: Some(selection) } => {
+                let frame = &frames[*selection];
+                Some(SelectionInfo::Frame {
+                    name: frame.name(),
+                    total: frame.total,
+                    self_time: frame.self_time,
+                })
+            }
             _ => None
         }
     }
 
     pub fn hover(&mut self, layout: &Layout, coord: (f64, f64)) {
         self.cursor = coord;
-        self.derived.hover(self.cursor, self.span, layout);
+        self.derived.hover(self.cursor, self.span, self.arrow_mode, layout);
     }
 
     pub fn cursor_time(&self) -> u64 {
         ((self.span.begin as f64) * (1.0 - self.cursor.0) + (self.span.end as f64) * self.cursor.0) as u64
     }
 
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
     pub fn span_time(&self) -> u64 {
         self.span.end - self.span.begin
     }
@@ -181,12 +518,20 @@ impl View {
         self.invalidate(layout);
     }
 
+    /// Move the window to show `span`, centering it if it fits and zooming out to fit it if not.
+    pub fn show(&mut self, layout: &Layout, span: Span) {
+        let width = std::cmp::max(self.span_time(), (span.end - span.begin) + (span.end - span.begin) / 5);
+        let center = span.begin + (span.end - span.begin) / 2;
+        let begin = center.saturating_sub(width / 2);
+        self.set_span(layout, Span { begin, end: begin + width });
+    }
+
     pub fn set_span_full(&mut self, layout: &Layout) {
         self.set_span(layout, self.limits);
     }
 
     pub fn scroll(&mut self, layout: &Layout, offset: f64, scale: f64) {
-        if self.mode == Mode::Profile {
+        if self.mode != Mode::Trace {
             return;
         }
 
@@ -230,7 +575,7 @@ impl View {
         let secondary_selection = Color { r, g, b, a: 1.0 };
 
         match &self.derived.mode {
-            DerivedMode::Trace { rows, selection } => {
+            DerivedMode::Trace { rows, selection, .. } => {
                 if let Some(total) = rows.last().map(|r| r.limit) {
                     let (name, highlight) = if let Some(selection) = selection {
                         (Some(selection.name), secondary_selection)
@@ -239,12 +584,13 @@ impl View {
                     };
 
                     for row in rows {
+                        let (vertical_base, vertical_limit) = row_extent(row.base, total);
                         let region = Region {
                             logical_base: (self.span.begin as f32) / 1e9,
                             logical_limit: (self.span.end as f32) / 1e9,
 
-                            vertical_base: row.base / total,
-                            vertical_limit: row.limit / total,
+                            vertical_base,
+                            vertical_limit,
                         };
 
                         for subrow in &row.subrows {
@@ -256,6 +602,22 @@ impl View {
                                 highlight,
                                 region,
                             });
+                            // Failures go over the async tasks, underneath their polls.
+                            if subrow.key.2 && row.has_outcomes {
+                                res.push(DrawCommand::Outcomes {
+                                    key: LabelListKey(row.thread_id, row.row_id),
+                                    region,
+                                });
+                            }
+                            if subrow.key.2 && row.has_delays && self.show_delays {
+                                res.push(DrawCommand::Delays {
+                                    key: LabelListKey(row.thread_id, row.row_id),
+                                    region,
+                                });
+                            }
+                            if self.highlights.contains_key(&subrow.key) {
+                                res.push(DrawCommand::Highlight { key: subrow.key, region });
+                            }
 
                             if let Some(selection) = selection {
                                 if selection.key == subrow.key {
@@ -278,6 +640,36 @@ impl View {
                     }
                 }
 
+                res.push(DrawCommand::SimpleBox {
+                    color: Color { r: 0.93, g: 0.93, b: 0.93, a: 1.0 },
+                    region: SimpleRegion { left: 0.0, right: 1.0, top: 0.0, bottom: RULER_HEIGHT },
+                });
+                if let Some(ruler) = self.ruler() {
+                    let span_time = (self.span.end - self.span.begin) as f32;
+                    for (tick, is_major) in ruler.ticks {
+                        let x = (tick - self.span.begin) as f32 / span_time;
+                        let top = if is_major { RULER_LABEL_HEIGHT } else { 0.5 * (RULER_LABEL_HEIGHT + RULER_HEIGHT) };
+                        res.push(DrawCommand::SimpleBox {
+                            color: Color { r: 0.0, g: 0.0, b: 0.0, a: 0.8 },
+                            region: SimpleRegion { left: x, right: x + TICK_WIDTH, top, bottom: RULER_HEIGHT },
+                        });
+                    }
+                    if !self.arrows().is_empty() {
+                        res.push(DrawCommand::Arrows);
+                    }
+                    if !self.hops().is_empty() {
+                        res.push(DrawCommand::Hops);
+                    }
+                    res.push(DrawCommand::RulerLabels {
+                        region: Region {
+                            logical_base: (self.span.begin as f32) / 1e9,
+                            logical_limit: (self.span.end as f32) / 1e9,
+                            vertical_base: 0.0,
+                            vertical_limit: RULER_LABEL_HEIGHT,
+                        },
+                    });
+                }
+
                 if let Some(cursor_down) = self.cursor_down {
                     let (left, right) = minmaxf(cursor_down.0, self.cursor.0);
                     // let (bottom, top) = minmaxf(cursor_down.1, self.cursor.1);
@@ -289,7 +681,7 @@ impl View {
                             // top: top as f32,
                             // bottom: bottom as f32,
                             bottom: 0.0,
-                            top: 1.0,
+                            top: 1.0 - STATUS_HEIGHT,
                         },
                     })
                 }
@@ -305,8 +697,8 @@ impl View {
                             region: SimpleRegion {
                                 left: 0.0,
                                 right: 1.0,
-                                bottom: selection.thread_base / total_height,
-                                top: selection.thread_limit / total_height,
+                                bottom: profile_extent(selection.thread_base, total_height),
+                                top: profile_extent(selection.thread_limit, total_height),
                             },
                         });
                         res.push(DrawCommand::SimpleBox {
@@ -314,8 +706,8 @@ impl View {
                             region: SimpleRegion {
                                 left: 0.0,
                                 right: 1.0,
-                                bottom: selection.base / total_height,
-                                top: selection.limit / total_height,
+                                bottom: profile_extent(selection.base, total_height),
+                                top: profile_extent(selection.limit, total_height),
                             },
                         });
                     }
@@ -327,21 +719,109 @@ impl View {
                                 region: SimpleRegion {
                                     left: 0.0,
                                     right: row.time as f32 / total_time,
-                                    bottom: row.base / total_height,
-                                    top: row.limit / total_height,
+                                    bottom: profile_extent(row.base, total_height),
+                                    top: profile_extent(row.limit, total_height),
                                 },
                             })
                         }
                     }
                 }
             }
+            DerivedMode::Flame { frames, selection } => {
+                let height = flame_row_height(frames);
+                for (index, frame) in frames.iter().enumerate() {
+                    let (r, g, b) = hsl_to_rgb((frame.name().0 as f32 * 0.618) % 1.0, 0.6, 0.6);
+                    let lightness = if Some(index) == *selection { 0.3 } else { 0.0 };
+                    let top = frame.depth as f32 * height;
+                    res.push(DrawCommand::SimpleBox {
+                        color: Color { r: r + lightness, g: g + lightness, b: b + lightness, a: 1.0 },
+                        region: SimpleRegion {
+                            left: frame.left as f32,
+                            // Leave a gap between neighbours.
+                            right: f32::max(frame.left as f32, frame.right as f32 - 0.001),
+                            top,
+                            bottom: top + height * 0.95,
+                        },
+                    });
+                }
+                let levels = frames.iter().map(|frame| frame.depth + 1).max().unwrap_or(0);
+                for depth in 0..levels {
+                    let top = depth as f32 * height;
+                    res.push(DrawCommand::FlameLabels {
+                        depth,
+                        region: Region {
+                            logical_base: 0.0,
+                            logical_limit: 1.0,
+                            vertical_base: top,
+                            vertical_limit: top + height * 0.95,
+                        },
+                    });
+                }
+            }
         }
 
+        res.push(DrawCommand::SimpleBox {
+            color: Color { r: 0.2, g: 0.2, b: 0.2, a: 1.0 },
+            region: SimpleRegion { left: 0.0, right: 1.0, top: 1.0 - STATUS_HEIGHT, bottom: 1.0 },
+        });
+        res.push(DrawCommand::StatusBar {
+            region: Region {
+                logical_base: 0.0,
+                logical_limit: 1.0,
+                vertical_base: 1.0 - STATUS_HEIGHT,
+                vertical_limit: 1.0,
+            },
+        });
+        if !self.details.is_empty() {
+            let bottom = RULER_HEIGHT + DETAILS_LINE_HEIGHT * self.details.len() as f32;
+            res.push(DrawCommand::SimpleBox {
+                color: Color { r: 0.2, g: 0.2, b: 0.2, a: 0.85 },
+                region: SimpleRegion { left: 1.0 - DETAILS_WIDTH, right: 1.0, top: RULER_HEIGHT, bottom },
+            });
+            for line in 0..self.details.len() {
+                let top = RULER_HEIGHT + DETAILS_LINE_HEIGHT * line as f32;
+                res.push(DrawCommand::Details {
+                    line,
+                    region: Region {
+                        // Put the line's one logical unit of text across the panel.
+                        logical_base: -(1.0 - DETAILS_WIDTH) / DETAILS_WIDTH,
+                        logical_limit: 1.0,
+                        vertical_base: top,
+                        vertical_limit: top + DETAILS_LINE_HEIGHT,
+                    },
+                });
+            }
+        }
+        if self.search_box.is_some() {
+            res.push(DrawCommand::SimpleBox {
+                color: Color { r: 0.1, g: 0.2, b: 0.45, a: 1.0 },
+                region: SimpleRegion { left: 0.0, right: SEARCH_BOX_WIDTH, top: SEARCH_BOX_TOP, bottom: 1.0 - STATUS_HEIGHT },
+            });
+            // Text is laid out across one logical unit, so stretch that to clip it at the box's edge.
+            res.push(DrawCommand::SearchBox {
+                region: Region {
+                    logical_base: 0.0,
+                    logical_limit: 1.0 / SEARCH_BOX_WIDTH,
+                    vertical_base: SEARCH_BOX_TOP,
+                    vertical_limit: 1.0 - STATUS_HEIGHT,
+                },
+            });
+        }
         res
     }
 
     fn invalidate(&mut self, layout: &Layout) {
-        self.derived = derived(&self.filter, self.cursor, self.span, self.mode, layout);
+        self.derived = derived(&self.filter, self.cursor, self.span, self.mode, self.arrow_mode, &self.flame_root, layout);
+    }
+}
+
+// The time range we can show, which is nonempty even before anything's been traced.
+fn limits(layout: &Layout) -> Span {
+    let limits = layout.span_discounting_threads();
+    if limits.begin > limits.end {
+        Span { begin: 0, end: MIN_WIDTH as u64 }
+    } else {
+        limits
     }
 }
 
@@ -373,6 +853,8 @@ fn rows(filter: &HashSet<(ThreadId, RowId)>, span: Span, layout: &Layout) -> Vec
                     thread_id: ThreadId(tid),
                     row_id: RowId(rid),
                     subrows,
+                    has_outcomes: r.has_outcomes(),
+                    has_delays: !r.delays.is_empty(),
                     base,
                     limit: base + 1.0,
                 });
@@ -435,8 +917,7 @@ fn find_selection(cursor: (f64, f64), span: Span, rows: &[Row], layout: &Layout)
 
     if let Some(total) = rows.last().map(|r| r.limit) {
         for row in rows.iter() {
-            let vertical_base = row.base / total;
-            let vertical_limit = row.limit / total;
+            let (vertical_base, vertical_limit) = row_extent(row.base, total);
             if cursor.1 < vertical_base as f64 || cursor.1 >= vertical_limit as f64 {
                 continue;
             }
@@ -469,8 +950,8 @@ fn find_profile_selection(cursor: (f64, f64), span: Span, threads: &[ProfileThre
     if let Some(total_height) = threads.last().and_then(|t| t.rows.last().map(|r| r.limit)) {
         for thread in threads {
             for row in &thread.rows {
-                let base = row.base as f64 / total_height as f64;
-                let limit = row.limit as f64 / total_height as f64;
+                let base = profile_extent(row.base, total_height) as f64;
+                let limit = profile_extent(row.limit, total_height) as f64;
                 if cursor.1 >= base && cursor.1 <= limit {
                     let thread_base = thread.rows[0].base;
                     let thread_limit = thread.rows.last().unwrap().limit;
@@ -489,13 +970,70 @@ fn find_profile_selection(cursor: (f64, f64), span: Span, threads: &[ProfileThre
     None
 }
 
-fn compute_filtered_row_set(names: Option<&NameIdSet>, layout: &Layout) -> HashSet<(ThreadId, RowId)> {
+// How tall each level of the flame graph is.
+fn flame_row_height(frames: &[Frame]) -> f32 {
+    let depth = frames.iter().map(|frame| frame.depth + 1).max().unwrap_or(1);
+    f32::min(FLAME_ROW_HEIGHT, (1.0 - STATUS_HEIGHT) / depth as f32)
+}
+
+fn find_flame_selection(cursor: (f64, f64), frames: &[Frame]) -> Option<usize> {
+    let height = flame_row_height(frames) as f64;
+    frames.iter().position(|frame| {
+        let top = frame.depth as f64 * height;
+        cursor.1 >= top && cursor.1 < top + height && cursor.0 >= frame.left && cursor.0 < frame.right
+    })
+}
+
+// Where the profile's `base`, out of `total` rows, goes vertically, leaving room for the status bar.
+fn profile_extent(base: f32, total: f32) -> f32 {
+    base / total * (1.0 - STATUS_HEIGHT)
+}
+
+fn compute_highlights(tasks: Option<&TaskIdSet>, clip: Option<&HashMap<TaskId, Vec<Span>>>, fore_only: bool, layout: &Layout) -> HashMap<BoxListKey, Vec<Span>> {
+    let mut res = HashMap::new();
+    let tasks = match tasks {
+        Some(tasks) => tasks,
+        None => return res,
+    };
+    for (tid, t) in layout.threads.iter().enumerate() {
+        for (rid, r) in t.rows.iter().enumerate() {
+            for (chunk, is_back) in &[(&r.back, true), (&r.fore, false)] {
+                if fore_only && *is_back {
+                    continue;
+                }
+                for (index, &task) in chunk.tasks.iter().enumerate() {
+                    if tasks.contains(task) {
+                        let span = Span { begin: chunk.begins[index], end: chunk.ends[index] };
+                        let spans = res.entry(BoxListKey(ThreadId(tid), RowId(rid), *is_back))
+                            .or_insert_with(Vec::new);
+                        match clip.and_then(|clip| clip.get(&task)) {
+                            Some(clip) => {
+                                for c in clip {
+                                    let begin = std::cmp::max(span.begin, c.begin);
+                                    let end = std::cmp::min(span.end, c.end);
+                                    if begin < end {
+                                        spans.push(Span { begin, end });
+                                    }
+                                }
+                            }
+                            None => spans.push(span),
+                        }
+                    }
+                }
+            }
+        }
+    }
+    res.retain(|_, spans| !spans.is_empty());
+    res
+}
+
+fn compute_filtered_row_set(tasks: Option<&TaskIdSet>, layout: &Layout) -> HashSet<(ThreadId, RowId)> {
     let mut res = HashSet::new();
 
-    if let Some(names) = names {
+    if let Some(tasks) = tasks {
         for (tid, t) in layout.threads.iter().enumerate() {
             for (rid, r) in t.rows.iter().enumerate() {
-                if r.name_set.overlaps(names) {
+                if r.fore.tasks.iter().chain(&r.back.tasks).any(|&task| tasks.contains(task)) {
                     res.insert((ThreadId(tid), RowId(rid)));
                 }
             }
@@ -511,17 +1049,82 @@ fn compute_filtered_row_set(names: Option<&NameIdSet>, layout: &Layout) -> HashS
     res
 }
 
-fn derived(filter: &HashSet<(ThreadId, RowId)>, cursor: (f64, f64), span: Span, mode: Mode, layout: &Layout) -> Derived {
+// Where a moment in a row is in window coordinates, if we're showing the row.
+fn row_points(rows: &[Row], span: Span) -> impl Fn((ThreadId, RowId, u64)) -> Option<(f32, f32)> {
+    let total = rows.last().map_or(0.0, |row| row.limit);
+    let bases: HashMap<_, _> = rows.iter().map(|r| ((r.thread_id, r.row_id), r.base)).collect();
+    let span_time = (span.end - span.begin) as f32;
+    move |(thread, row, time)| {
+        bases.get(&(thread, row)).map(|&base| {
+            let (top, bottom) = row_extent(base, total);
+            let x = (time as f64 - span.begin as f64) as f32 / span_time;
+            (x, 0.5 * (top + bottom))
+        })
+    }
+}
+
+// Whether to draw an arrow between two moments, given whether it's for the selected span.
+fn arrow_visible(arrow_mode: ArrowMode, is_selected: bool, from: u64, to: u64, span: Span) -> bool {
+    match arrow_mode {
+        ArrowMode::Off => false,
+        ArrowMode::Selected => is_selected,
+        ArrowMode::Visible => {
+            let (begin, end) = if from <= to { (from, to) } else { (to, from) };
+            is_selected || (begin <= span.end && end >= span.begin)
+        }
+    }
+}
+
+fn arrows(arrow_mode: ArrowMode, rows: &[Row], selection: Option<&InternalSelectionInfo>, span: Span, layout: &Layout) -> Vec<Arrow> {
+    let selected = selection.map(|s| s.task);
+    let point = row_points(rows, span);
+
+    let mut res = Vec::new();
+    for wakeup in &layout.wakeups {
+        let is_selected = selected == Some(wakeup.waking) || selected == Some(wakeup.parked);
+        if !arrow_visible(arrow_mode, is_selected, wakeup.from.2, wakeup.to.2, span) {
+            continue;
+        }
+        // Both ends have to be in rows we're showing.
+        if let (Some(from), Some(to)) = (point(wakeup.from), point(wakeup.to)) {
+            res.push(Arrow { from, to, selected: is_selected });
+        }
+    }
+    res
+}
+
+fn hops(arrow_mode: ArrowMode, rows: &[Row], selection: Option<&InternalSelectionInfo>, span: Span, layout: &Layout) -> Vec<Arrow> {
+    let selected = selection.map(|s| s.task);
+    let point = row_points(rows, span);
+
+    let mut res = Vec::new();
+    for hop in &layout.hops {
+        let is_selected = selected == Some(hop.task);
+        if !arrow_visible(arrow_mode, is_selected, hop.from.2, hop.to.2, span) {
+            continue;
+        }
+        if let (Some(from), Some(to)) = (point(hop.from), point(hop.to)) {
+            res.push(Arrow { from, to, selected: is_selected });
+        }
+    }
+    res
+}
+
+fn derived(filter: &HashSet<(ThreadId, RowId)>, cursor: (f64, f64), span: Span, mode: Mode, arrow_mode: ArrowMode, flame_root: &[NameId], layout: &Layout) -> Derived {
     match mode {
         Mode::Trace => {
             let rows = rows(filter, span, layout);
 
             let selection = find_selection(cursor, span, &rows, layout);
+            let arrows = arrows(arrow_mode, &rows, selection.as_ref(), span, layout);
+            let hops = hops(arrow_mode, &rows, selection.as_ref(), span, layout);
 
             Derived {
                 mode: DerivedMode::Trace {
                     rows,
                     selection,
+                    arrows,
+                    hops,
                 },
             }
         }
@@ -537,18 +1140,36 @@ fn derived(filter: &HashSet<(ThreadId, RowId)>, cursor: (f64, f64), span: Span,
                 },
             }
         }
+        Mode::Flame => {
+            let frames = flame::frames(filter, span, flame_root, layout);
+            let selection = find_flame_selection(cursor, &frames);
+            Derived {
+                mode: DerivedMode::Flame {
+                    frames,
+                    selection,
+                },
+            }
+        }
     }
 }
 
 impl Derived {
-    fn hover(&mut self, cursor: (f64, f64), span: Span, layout: &Layout) {
+    fn hover(&mut self, cursor: (f64, f64), span: Span, arrow_mode: ArrowMode, layout: &Layout) {
         match self.mode {
-            DerivedMode::Trace { ref rows, ref mut selection } => {
+            DerivedMode::Trace { ref rows, ref mut selection, ref mut arrows, ref mut hops } => {
+                let previous = selection.map(|s| s.task);
                 *selection = find_selection(cursor, span, rows, layout);
+                if selection.map(|s| s.task) != previous {
+                    *arrows = self::arrows(arrow_mode, rows, selection.as_ref(), span, layout);
+                    *hops = self::hops(arrow_mode, rows, selection.as_ref(), span, layout);
+                }
             }
             DerivedMode::Profile { ref threads, ref mut selection } => {
                 *selection = find_profile_selection(cursor, span, threads, layout)
             }
+            DerivedMode::Flame { ref frames, ref mut selection } => {
+                *selection = find_flame_selection(cursor, frames)
+            }
         }
     }
 }
@@ -561,11 +1182,19 @@ enum DerivedMode {
     Trace {
         rows: Vec<Row>,
         selection: Option<InternalSelectionInfo>,
+        arrows: Vec<Arrow>,
+        // Arrows for tasks moving between threads.
+        hops: Vec<Arrow>,
     },
     Profile {
         threads: Vec<ProfileThread>,
         selection: Option<InternalProfileSelectionInfo>,
     },
+    Flame {
+        frames: Vec<Frame>,
+        // Index of the frame under the cursor.
+        selection: Option<usize>,
+    },
 }
 
 struct Subrow {
@@ -578,6 +1207,8 @@ struct Row {
     thread_id: ThreadId,
     row_id: RowId,
     subrows: Vec<Subrow>,
+    has_outcomes: bool,
+    has_delays: bool,
     base: f32,
     limit: f32,
 }
@@ -593,3 +1224,34 @@ struct ProfileRow {
     base: f32,
     limit: f32,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ruler, tick_spacing, format_time, time_unit};
+    use crate::db::Span;
+
+    #[test]
+    fn test_tick_spacing() {
+        assert_eq!(tick_spacing(1_000), (200, 50));
+        assert_eq!(tick_spacing(8_000_000), (1_000_000, 200_000));
+        assert_eq!(tick_spacing(30_000_000), (5_000_000, 1_000_000));
+        assert_eq!(tick_spacing(3), (1, 1));
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(2_500_000, 500_000, time_unit(20_000_000)), "2.5 ms");
+        assert_eq!(format_time(3_000_000_000, 1_000_000_000, time_unit(20_000_000_000)), "3 s");
+        assert_eq!(format_time(1_250, 250, time_unit(2_000)), "1250 ns");
+    }
+
+    #[test]
+    fn test_ruler() {
+        let r = ruler(Span { begin: 1_150, end: 2_150 }, 1_000);
+        let labels: Vec<_> = r.labels.iter().map(|(label, _)| label.as_str()).collect();
+        assert_eq!(labels, vec!["200 ns", "400 ns", "600 ns", "800 ns", "1000 ns"]);
+        assert_eq!(r.ticks.first(), Some(&(1_150, false)));
+        assert_eq!(r.ticks.iter().filter(|(_, major)| *major).count(), 5);
+        assert_eq!(r.labels[0].1, Span { begin: 1_200, end: 1_400 });
+    }
+}